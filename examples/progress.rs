@@ -0,0 +1,76 @@
+use egui_elm::prelude::*;
+use std::time::Duration;
+
+const STEPS: u32 = 20;
+
+#[derive(Default)]
+struct ProgressApp {
+    progress: Option<u32>,
+    finished: bool,
+}
+
+#[derive(Clone)]
+enum Message {
+    Start,
+    Progress(u32),
+    Finished,
+}
+
+fn init(_ctx: &egui::Context) -> (ProgressApp, Command<Message>) {
+    (ProgressApp::default(), Command::none())
+}
+
+fn update(model: &mut ProgressApp, message: Message) -> Command<Message> {
+    match message {
+        Message::Start => {
+            model.progress = Some(0);
+            model.finished = false;
+            let job = async_stream::stream! {
+                for step in 1..=STEPS {
+                    tokio::time::sleep(Duration::from_millis(150)).await;
+                    yield Message::Progress(step);
+                }
+                yield Message::Finished;
+            };
+            Command::stream(job)
+        }
+        Message::Progress(step) => {
+            model.progress = Some(step);
+            Command::none()
+        }
+        Message::Finished => {
+            model.finished = true;
+            Command::none()
+        }
+    }
+}
+
+fn view(model: &ProgressApp, ctx: &egui::Context, ui_ctx: &ViewContext<Message>) {
+    egui::CentralPanel::default().show(ctx, |ui| {
+        ui.heading("Streaming command");
+
+        let running = model.progress.is_some() && !model.finished;
+        if ui
+            .add_enabled(!running, egui::Button::new("Start job"))
+            .clicked()
+        {
+            ui_ctx.send(Message::Start);
+        }
+
+        if let Some(step) = model.progress {
+            ui.add(egui::ProgressBar::new(step as f32 / STEPS as f32).show_percentage());
+        }
+        if model.finished {
+            ui.colored_label(egui::Color32::LIGHT_GREEN, "Job finished");
+        }
+    });
+}
+
+fn subscription(_model: &ProgressApp) -> Subscription<Message> {
+    Subscription::none()
+}
+
+fn main() -> eframe::Result<()> {
+    let program = Program::new(init, update, view, subscription);
+    egui_elm::app::run(program, "Progress")
+}
@@ -1,22 +1,193 @@
-use std::future::Future;
+use std::{
+    any::Any,
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use eframe::egui;
-use futures::{pin_mut, Stream, StreamExt};
+use futures::{pin_mut, FutureExt, Stream, StreamExt};
 use tokio::{
     runtime::{Handle, Runtime},
-    sync::mpsc,
+    sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore},
     task::JoinHandle,
 };
 
 use crate::{
-    command::Command,
+    command::{Action, Command, CommandError, CommandKey, CommandOutput, ReplyFn, Task, UiAction},
     program::Program,
-    subscription::{IntoSubscription, SubscriptionToken},
+    subscription::{FrameEvent, IntoSubscription, Listener, SubscriptionToken},
     view::ViewContext,
 };
 
 const MAILBOX_CAPACITY: usize = 512;
 
+/// Frames to wait for the `Event::Paste` answering a clipboard read before giving up.
+///
+/// eframe turns the request into an event only after painting the following frame, and
+/// sends nothing at all for an empty clipboard.
+const PASTE_TIMEOUT_FRAMES: u32 = 4;
+
+/// Frames to wait for a requested screenshot before dropping the request.
+///
+/// Screenshots usually arrive with the next frame's input, but backends that cannot take
+/// them never answer.
+const SCREENSHOT_TIMEOUT_FRAMES: u32 = 8;
+
+/// Sends an envelope to the UI thread and wakes it up, so the envelope is handled without
+/// waiting for user input. Returns `false` once the app is gone.
+async fn deliver<Message>(
+    sender: &mpsc::Sender<Envelope<Message>>,
+    ctx: &egui::Context,
+    envelope: Envelope<Message>,
+) -> bool
+where
+    Message: Send + 'static,
+{
+    let delivered = sender.send(envelope).await.is_ok();
+    ctx.request_repaint();
+    delivered
+}
+
+/// Identifier assigned to every command task spawned by the runtime.
+type TaskId = u64;
+
+/// Item travelling through the mailbox, tagged with the task that produced it.
+pub(crate) struct Envelope<Message>
+where
+    Message: Send + 'static,
+{
+    task: Option<TaskId>,
+    payload: Payload<Message>,
+}
+
+enum Payload<Message>
+where
+    Message: Send + 'static,
+{
+    Output(CommandOutput<Message>),
+    Failure(TaskFailure),
+}
+
+impl<Message> Envelope<Message>
+where
+    Message: Send + 'static,
+{
+    /// Wraps a message that was not produced by a command task, e.g. one sent from the view.
+    pub(crate) fn untracked(message: Message) -> Self {
+        Self {
+            task: None,
+            payload: Payload::Output(CommandOutput::Message(message)),
+        }
+    }
+}
+
+/// Describes a command task or subscription stream that panicked.
+///
+/// Passed to the handler registered with
+/// [`Program::with_task_failure_handler`](crate::program::Program::with_task_failure_handler).
+#[derive(Clone, Debug)]
+pub struct TaskFailure {
+    origin: TaskOrigin,
+    label: Option<Cow<'static, str>>,
+    message: String,
+}
+
+/// Kind of work that panicked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskOrigin {
+    /// A task spawned for a [`Command`].
+    Command,
+    /// The stream of a subscription.
+    Subscription,
+}
+
+impl TaskFailure {
+    fn from_panic(
+        origin: TaskOrigin,
+        label: Option<Cow<'static, str>>,
+        payload: Box<dyn Any + Send>,
+    ) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => (*message).to_owned(),
+                Err(_) => "Box<dyn Any>".to_owned(),
+            },
+        };
+
+        Self {
+            origin,
+            label,
+            message,
+        }
+    }
+
+    /// Returns whether a command or a subscription panicked.
+    pub fn origin(&self) -> TaskOrigin {
+        self.origin
+    }
+
+    /// Returns the label of the failed command, if it was given one with
+    /// [`Command::with_label`].
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Returns the panic payload rendered as text.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for TaskFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let origin = match self.origin {
+            TaskOrigin::Command => "command",
+            TaskOrigin::Subscription => "subscription",
+        };
+        match &self.label {
+            Some(label) => write!(f, "{origin} `{label}` panicked: {}", self.message),
+            None => write!(f, "{origin} panicked: {}", self.message),
+        }
+    }
+}
+
+/// Labeled command task that is still running.
+///
+/// Listed by [`ViewContext::pending_tasks`] for every task named with
+/// [`Command::with_label`] or [`Command::labeled`].
+#[derive(Clone, Debug)]
+pub struct PendingTask {
+    label: Cow<'static, str>,
+    started: Instant,
+}
+
+impl PendingTask {
+    /// Returns the label the task was given.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Returns when the runtime spawned the task.
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    /// Returns how long the task has been running, including time spent waiting for its
+    /// concurrency group.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
 /// Runs the supplied Elm program using eframe's native runner with default options.
 ///
 /// To customize the renderer (e.g. switch between `glow` and `wgpu`) or any other
@@ -50,7 +221,13 @@ where
         Box::new(move |cc| {
             let runtime = TokioRuntime::try_current_or_new()?;
             let (model, command) = (program.init)(&cc.egui_ctx);
-            let app: Box<dyn eframe::App> = Box::new(ElmApp::new(program, model, command, runtime));
+            let app: Box<dyn eframe::App> = Box::new(ElmApp::new(
+                program,
+                model,
+                command,
+                runtime,
+                cc.egui_ctx.clone(),
+            ));
             Ok(app)
         }),
     )
@@ -81,6 +258,121 @@ impl TokioRuntime {
     }
 }
 
+struct RunningTask {
+    id: TaskId,
+    key: Option<CommandKey>,
+    label: Option<Cow<'static, str>>,
+    started: Instant,
+    handle: JoinHandle<()>,
+    cancelled: bool,
+}
+
+/// Bookkeeping for command tasks that are still running.
+#[derive(Default)]
+struct TaskRegistry {
+    tasks: Vec<RunningTask>,
+    next_id: TaskId,
+}
+
+impl TaskRegistry {
+    fn next_id(&mut self) -> TaskId {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn insert(
+        &mut self,
+        id: TaskId,
+        key: Option<CommandKey>,
+        label: Option<Cow<'static, str>>,
+        handle: JoinHandle<()>,
+    ) {
+        self.tasks.push(RunningTask {
+            id,
+            key,
+            label,
+            started: Instant::now(),
+            handle,
+            cancelled: false,
+        });
+    }
+
+    fn cancel(&mut self, key: &CommandKey) {
+        for task in &mut self.tasks {
+            if task.key.as_ref() == Some(key) {
+                task.handle.abort();
+                task.cancelled = true;
+            }
+        }
+    }
+
+    /// Returns `true` if messages from the task must not reach `update` anymore.
+    fn is_cancelled(&self, id: TaskId) -> bool {
+        self.tasks
+            .iter()
+            .any(|task| task.id == id && task.cancelled)
+    }
+
+    /// Collects the ids of tasks that have already finished.
+    ///
+    /// Finished tasks cannot send anything else, so once the mailbox has been drained they
+    /// can be dropped with [`TaskRegistry::remove`] without letting stale messages through.
+    fn finished(&self) -> Vec<TaskId> {
+        self.tasks
+            .iter()
+            .filter(|task| task.handle.is_finished())
+            .map(|task| task.id)
+            .collect()
+    }
+
+    /// Lists the labeled tasks that are still running, oldest first.
+    fn pending(&self) -> Arc<[PendingTask]> {
+        self.tasks
+            .iter()
+            .filter(|task| !task.cancelled && !task.handle.is_finished())
+            .filter_map(|task| {
+                Some(PendingTask {
+                    label: task.label.clone()?,
+                    started: task.started,
+                })
+            })
+            .collect()
+    }
+
+    fn remove(&mut self, ids: &[TaskId]) {
+        self.tasks.retain(|task| !ids.contains(&task.id));
+    }
+
+    fn abort_all(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.handle.abort();
+        }
+    }
+}
+
+/// Listener of the current subscription.
+struct RunningListener<Message> {
+    listener: Listener<Message>,
+    /// Set until the listener has been given the current system theme.
+    fresh: bool,
+}
+
+/// Queue of tasks waiting for a permit of their concurrency group.
+type GroupQueue = mpsc::UnboundedSender<oneshot::Sender<OwnedSemaphorePermit>>;
+
+/// Stream of a subscription child that is currently running.
+struct RunningSubscription {
+    token: Option<SubscriptionToken>,
+    handle: JoinHandle<()>,
+    /// Set when the stream panicked, so the next reconciliation starts it again.
+    failed: Arc<AtomicBool>,
+}
+
+type ScreenshotCallback<Message> = ReplyFn<Arc<egui::ColorImage>, Message>;
+
+/// User data attached to screenshot requests so the resulting event can be correlated.
+struct ScreenshotRequest(u64);
+
 struct ElmApp<Model, Message, Sub>
 where
     Model: Send + 'static,
@@ -90,10 +382,26 @@ where
     program: Program<Model, Message, Sub>,
     model: Model,
     runtime: TokioRuntime,
-    mailbox_sender: mpsc::Sender<Message>,
-    mailbox_receiver: mpsc::Receiver<Message>,
-    subscription_task: Option<JoinHandle<()>>,
-    subscription_token: Option<SubscriptionToken>,
+    /// Used by tasks to wake the UI thread when they deliver something.
+    ctx: egui::Context,
+    mailbox_sender: mpsc::Sender<Envelope<Message>>,
+    mailbox_receiver: mpsc::Receiver<Envelope<Message>>,
+    tasks: TaskRegistry,
+    groups: HashMap<Cow<'static, str>, GroupQueue>,
+    ui_actions: VecDeque<UiAction<Message>>,
+    clipboard_reads: Vec<ReplyFn<String, Message>>,
+    /// Frames that passed without a paste while clipboard reads were pending.
+    clipboard_wait: u32,
+    /// Pending screenshot requests with the number of frames they have waited.
+    screenshots: HashMap<u64, (u32, ScreenshotCallback<Message>)>,
+    next_screenshot: u64,
+    subscriptions: Vec<RunningSubscription>,
+    subscription_listeners: Vec<RunningListener<Message>>,
+    /// Whether the debug warning about restarted untokened subscriptions was logged.
+    warned_untokened: bool,
+    system_theme: Option<egui::Theme>,
+    /// Whether the current subscription renders frames continuously.
+    animating: bool,
 }
 
 impl<Model, Message, Sub> ElmApp<Model, Message, Sub>
@@ -107,17 +415,34 @@ where
         model: Model,
         initial_command: Command<Message>,
         runtime: TokioRuntime,
+        ctx: egui::Context,
     ) -> Self {
         let (mailbox_sender, mailbox_receiver) = mpsc::channel(MAILBOX_CAPACITY);
+        let groups = program
+            .concurrency_limits
+            .iter()
+            .map(|(group, limit)| (group.clone(), Self::spawn_group(&runtime, *limit)))
+            .collect();
 
         let mut app = Self {
             program,
             model,
             runtime,
+            ctx,
             mailbox_sender: mailbox_sender.clone(),
             mailbox_receiver,
-            subscription_task: None,
-            subscription_token: None,
+            tasks: TaskRegistry::default(),
+            groups,
+            ui_actions: VecDeque::new(),
+            clipboard_reads: Vec::new(),
+            clipboard_wait: 0,
+            screenshots: HashMap::new(),
+            next_screenshot: 0,
+            subscriptions: Vec::new(),
+            subscription_listeners: Vec::new(),
+            warned_untokened: false,
+            system_theme: None,
+            animating: false,
         };
 
         app.enqueue_command(initial_command);
@@ -126,55 +451,432 @@ where
     }
 
     fn enqueue_command(&mut self, command: Command<Message>) {
-        for future in command.into_futures() {
-            let sender = self.mailbox_sender.clone();
-            self.runtime.spawn(async move {
-                if let Some(message) = future.await {
-                    let _ = sender.send(message).await;
+        let actions = command.into_actions();
+
+        // Supersede before spawning so tasks of the same batch do not abort each other.
+        for action in &actions {
+            if let Action::Spawn(Task {
+                key: Some(key),
+                latest: true,
+                ..
+            }) = action
+            {
+                self.tasks.cancel(key);
+            }
+        }
+
+        for action in actions {
+            match action {
+                Action::Spawn(task) => self.spawn_task(task),
+                Action::Cancel(key) => self.tasks.cancel(&key),
+                Action::Ui(action) => self.ui_actions.push_back(action),
+            }
+        }
+    }
+
+    /// Starts the dispatcher that hands out a group's permits in the order tasks were spawned.
+    fn spawn_group(runtime: &TokioRuntime, limit: usize) -> GroupQueue {
+        let (queue, mut waiting) = mpsc::unbounded_channel::<oneshot::Sender<_>>();
+        let semaphore = Arc::new(Semaphore::new(limit));
+        runtime.spawn(async move {
+            while let Some(turn) = waiting.recv().await {
+                // Skip tasks that were aborted while queued.
+                if turn.is_closed() {
+                    continue;
                 }
+                let Ok(permit) = semaphore.clone().acquire_owned().await else {
+                    break;
+                };
+                // If the task went away in the meantime the permit is released right here.
+                let _ = turn.send(permit);
+            }
+        });
+        queue
+    }
+
+    fn spawn_task(&mut self, task: Task<Message>) {
+        let Task {
+            mut stream,
+            key,
+            group,
+            label,
+            ..
+        } = task;
+        let id = self.tasks.next_id();
+        let sender = self.mailbox_sender.clone();
+        let ctx = self.ctx.clone();
+        // Take a place in the group's queue now, so tasks start in the order they were spawned.
+        let turn = group
+            .and_then(|group| self.groups.get(&group))
+            .map(|queue| {
+                let (turn, permit) = oneshot::channel();
+                let _ = queue.send(turn);
+                permit
             });
-        }
+        let name = label.clone();
+        let handle = self.runtime.spawn(async move {
+            // Queued tasks wait here; the permit is held until the task is done.
+            let _permit = match turn {
+                Some(permit) => permit.await.ok(),
+                None => None,
+            };
+            let forward = async {
+                while let Some(output) = stream.next().await {
+                    let envelope = Envelope {
+                        task: Some(id),
+                        payload: Payload::Output(output),
+                    };
+                    if !deliver(&sender, &ctx, envelope).await {
+                        break;
+                    }
+                }
+            };
+            let outcome = AssertUnwindSafe(forward).catch_unwind().await;
+            // Wake the UI thread even if nothing was sent, so the task registry is refreshed.
+            ctx.request_repaint();
+            if let Err(panic) = outcome {
+                let envelope = Envelope {
+                    task: Some(id),
+                    payload: Payload::Failure(TaskFailure::from_panic(
+                        TaskOrigin::Command,
+                        label,
+                        panic,
+                    )),
+                };
+                deliver(&sender, &ctx, envelope).await;
+            }
+        });
+        self.tasks.insert(id, key, name, handle);
     }
 
-    fn spawn_stream<S>(
-        runtime: &TokioRuntime,
-        stream: S,
-        sender: mpsc::Sender<Message>,
-    ) -> JoinHandle<()>
+    fn spawn_stream<S>(&self, token: Option<SubscriptionToken>, stream: S) -> RunningSubscription
     where
         S: Stream<Item = Message> + Send + 'static,
     {
-        runtime.spawn(async move {
-            pin_mut!(stream);
-            while let Some(message) = stream.next().await {
-                if sender.send(message).await.is_err() {
-                    break;
+        let sender = self.mailbox_sender.clone();
+        let ctx = self.ctx.clone();
+        let failed = Arc::new(AtomicBool::new(false));
+        let failure = failed.clone();
+        let handle = self.runtime.spawn(async move {
+            let forward = async {
+                pin_mut!(stream);
+                while let Some(message) = stream.next().await {
+                    if !deliver(&sender, &ctx, Envelope::untracked(message)).await {
+                        break;
+                    }
                 }
+            };
+            if let Err(panic) = AssertUnwindSafe(forward).catch_unwind().await {
+                failure.store(true, Ordering::Relaxed);
+                let envelope = Envelope {
+                    task: None,
+                    payload: Payload::Failure(TaskFailure::from_panic(
+                        TaskOrigin::Subscription,
+                        None,
+                        panic,
+                    )),
+                };
+                deliver(&sender, &ctx, envelope).await;
             }
-        })
+        });
+        RunningSubscription {
+            token,
+            handle,
+            failed,
+        }
     }
 
+    /// Reconciles the running streams with the current subscription, child by child.
+    ///
+    /// Children whose token matches a running stream keep it, new children are spawned and
+    /// streams that are no longer requested are aborted. Children without a token cannot be
+    /// matched and are restarted every time. Streams that panicked are started again.
     fn restart_subscription(&mut self) {
-        let subscription = (self.program.subscription)(&self.model);
-        let identity = subscription.identity();
+        let subscription = (self.program.subscription)(&self.model).into_subscription();
+        self.animating = subscription.animates();
+        let (children, listeners) = subscription.into_parts();
+
+        // Listeners already seen keep their state; new ones still need the current theme.
+        let mut previous_listeners = std::mem::take(&mut self.subscription_listeners);
+        for listener in listeners {
+            let index = previous_listeners
+                .iter()
+                .position(|running| running.listener.token == listener.token);
+            let fresh = index.map_or(true, |index| previous_listeners.swap_remove(index).fresh);
+            self.subscription_listeners
+                .push(RunningListener { listener, fresh });
+        }
+
+        let mut previous = std::mem::take(&mut self.subscriptions);
+        if cfg!(debug_assertions)
+            && !self.warned_untokened
+            && children.iter().any(|child| child.token.is_none())
+            && previous.iter().any(|running| running.token.is_none())
+        {
+            self.warned_untokened = true;
+            log::warn!(
+                "restarting a subscription without identity after a message; \
+                 return a token from `IntoSubscription::identity` to keep it running"
+            );
+        }
+        for child in children {
+            let running = child.token.as_ref().and_then(|token| {
+                previous.iter().position(|running| {
+                    running.token.as_ref() == Some(token) && !running.failed.load(Ordering::Relaxed)
+                })
+            });
+            let running = match running {
+                Some(index) => previous.swap_remove(index),
+                None => self.spawn_stream(child.token, child.stream),
+            };
+            self.subscriptions.push(running);
+        }
+
+        for running in previous {
+            running.handle.abort();
+        }
+    }
+
+    fn drain_mailbox(&mut self) {
+        let finished = self.tasks.finished();
+        while let Ok(envelope) = self.mailbox_receiver.try_recv() {
+            if let Some(id) = envelope.task {
+                if self.tasks.is_cancelled(id) {
+                    continue;
+                }
+            }
+            match envelope.payload {
+                Payload::Output(output) => self.handle_output(output),
+                Payload::Failure(failure) => self.handle_failure(failure),
+            }
+        }
+        self.tasks.remove(&finished);
+    }
+
+    /// Applies effects that need the egui context or the frame, in the order they were issued.
+    fn apply_ui_actions(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        while let Some(action) = self.ui_actions.pop_front() {
+            match action {
+                UiAction::Viewport(command) => ctx.send_viewport_cmd(command),
+                UiAction::CopyText(text) => ctx.copy_text(text),
+                UiAction::ReadClipboard(read) => {
+                    // The integration answers with an `Event::Paste` in a later frame's input.
+                    if self.clipboard_reads.is_empty() {
+                        ctx.send_viewport_cmd(egui::ViewportCommand::RequestPaste);
+                    }
+                    self.clipboard_reads.push(read);
+                }
+                UiAction::Focus(id) => ctx.memory_mut(|memory| memory.request_focus(id)),
+                UiAction::SurrenderFocus(id) => {
+                    ctx.memory_mut(|memory| memory.surrender_focus(id));
+                }
+                UiAction::ScrollTo(id, align) => {
+                    if let Some(response) = ctx.read_response(id) {
+                        response.scroll_to_me(align);
+                    }
+                }
+                UiAction::Screenshot(capture) => {
+                    self.next_screenshot += 1;
+                    let request = ScreenshotRequest(self.next_screenshot);
+                    self.screenshots.insert(request.0, (0, capture));
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Screenshot(egui::UserData::new(
+                        request,
+                    )));
+                }
+                UiAction::Context(run) => {
+                    if let Some(output) = run(ctx) {
+                        self.handle_output(output);
+                    }
+                }
+                UiAction::Effect(run) => {
+                    let command = run(ctx);
+                    self.enqueue_command(command);
+                }
+                UiAction::StorageSet(key, value) => {
+                    if let Some(storage) = frame.storage_mut() {
+                        storage.set_string(&key, value);
+                    }
+                }
+                UiAction::StorageGet(key, read) => {
+                    let value = frame.storage().and_then(|storage| storage.get_string(&key));
+                    self.handle_output(read(value));
+                }
+                UiAction::FlushStorage => {
+                    if let Some(storage) = frame.storage_mut() {
+                        storage.flush();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Passes the event to the listeners of the current subscription.
+    fn dispatch_frame_event(&mut self, event: &FrameEvent<'_>) {
+        // Collect first: handling a message may replace the listeners.
+        let messages: Vec<_> = self
+            .subscription_listeners
+            .iter_mut()
+            .filter_map(|running| (running.listener.callback)(event))
+            .collect();
+        for message in messages {
+            self.handle_message(message);
+        }
+    }
+
+    /// Reports theme changes to all listeners, and the current theme to listeners that
+    /// appeared since the last frame.
+    fn observe_system_theme(&mut self, raw_input: &egui::RawInput) {
+        let changed = raw_input
+            .system_theme
+            .filter(|theme| self.system_theme != Some(*theme));
+        if changed.is_some() {
+            self.system_theme = changed;
+        }
 
-        if let (Some(previous), Some(current)) = (&self.subscription_token, &identity) {
-            if previous == current {
-                return;
+        let mut messages = Vec::new();
+        if let Some(theme) = self.system_theme {
+            let event = FrameEvent::SystemThemeChanged(theme);
+            messages.extend(
+                self.subscription_listeners
+                    .iter_mut()
+                    .filter(|running| changed.is_some() || running.fresh)
+                    .filter_map(|running| (running.listener.callback)(&event)),
+            );
+        }
+        for running in &mut self.subscription_listeners {
+            running.fresh = false;
+        }
+        for message in messages {
+            self.handle_message(message);
+        }
+    }
+
+    /// Fires frame subscriptions for the frame that is about to be rendered.
+    fn dispatch_frame_tick(&mut self, ctx: &egui::Context) {
+        if !self.animating {
+            return;
+        }
+        let dt = ctx.input(|input| input.stable_dt);
+        self.dispatch_frame_event(&FrameEvent::Frame {
+            dt: Duration::try_from_secs_f32(dt).unwrap_or_default(),
+            now: Instant::now(),
+        });
+    }
+
+    /// Returns `true` if another frame is needed right away: to animate, to handle messages
+    /// sent from the view or to wait for a reply from the integration. Tasks and
+    /// subscriptions wake the UI thread themselves when they deliver something.
+    fn needs_repaint(&self) -> bool {
+        self.animating
+            || !self.mailbox_receiver.is_empty()
+            || !self.ui_actions.is_empty()
+            || !self.clipboard_reads.is_empty()
+            || !self.screenshots.is_empty()
+            || self
+                .subscription_listeners
+                .iter()
+                .any(|running| running.fresh)
+    }
+
+    /// Feeds the frame's input events to the listeners of the current subscription.
+    fn dispatch_input_events(&mut self, raw_input: &egui::RawInput) {
+        if self.subscription_listeners.is_empty() {
+            return;
+        }
+        for event in &raw_input.events {
+            self.dispatch_frame_event(&FrameEvent::Input(event));
+        }
+    }
+
+    /// Answers pending clipboard reads and forwards paste events to the paste handler.
+    fn handle_paste_events(&mut self, raw_input: &mut egui::RawInput) {
+        if !self.clipboard_reads.is_empty() {
+            // The paste we requested is consumed so it does not also land in a focused text field.
+            let index = raw_input
+                .events
+                .iter()
+                .position(|event| matches!(event, egui::Event::Paste(_)));
+            let text = match index.map(|index| raw_input.events.remove(index)) {
+                Some(egui::Event::Paste(text)) => Some(text),
+                _ => {
+                    self.clipboard_wait += 1;
+                    // An empty clipboard produces no event, so stop waiting eventually.
+                    (self.clipboard_wait >= PASTE_TIMEOUT_FRAMES).then(String::new)
+                }
+            };
+            if let Some(text) = text {
+                self.clipboard_wait = 0;
+                for read in std::mem::take(&mut self.clipboard_reads) {
+                    self.handle_output(read(text.clone()));
+                }
             }
         }
 
-        if let Some(handle) = self.subscription_task.take() {
-            handle.abort();
+        if let Some(on_paste) = self.program.on_paste {
+            let messages: Vec<_> = raw_input
+                .events
+                .iter()
+                .filter_map(|event| match event {
+                    egui::Event::Paste(text) => on_paste(text),
+                    _ => None,
+                })
+                .collect();
+            for message in messages {
+                self.handle_message(message);
+            }
+        }
+    }
+
+    /// Delivers screenshots requested through [`Command::screenshot`].
+    fn handle_screenshot_events(&mut self, raw_input: &egui::RawInput) {
+        if self.screenshots.is_empty() {
+            return;
+        }
+
+        for event in &raw_input.events {
+            let egui::Event::Screenshot {
+                user_data, image, ..
+            } = event
+            else {
+                continue;
+            };
+            let request = user_data
+                .data
+                .as_ref()
+                .and_then(|data| data.downcast_ref::<ScreenshotRequest>());
+            if let Some((_, capture)) =
+                request.and_then(|request| self.screenshots.remove(&request.0))
+            {
+                self.handle_output(capture(image.clone()));
+            }
+        }
+
+        self.screenshots.retain(|_, (frames, _)| {
+            *frames += 1;
+            *frames < SCREENSHOT_TIMEOUT_FRAMES
+        });
+    }
+
+    fn handle_output(&mut self, output: CommandOutput<Message>) {
+        match output {
+            CommandOutput::Message(message) => self.handle_message(message),
+            CommandOutput::Command(command) => self.enqueue_command(command),
+            CommandOutput::Error(error) => self.handle_error(error),
+        }
+    }
+
+    fn handle_error(&mut self, error: CommandError) {
+        match self.program.on_error {
+            Some(on_error) => self.handle_message(on_error(error)),
+            None => log::warn!("unhandled command error: {error}"),
         }
+    }
 
-        let stream = subscription.into_stream();
-        self.subscription_task = Some(Self::spawn_stream(
-            &self.runtime,
-            stream,
-            self.mailbox_sender.clone(),
-        ));
-        self.subscription_token = identity;
+    fn handle_failure(&mut self, failure: TaskFailure) {
+        match self.program.on_task_failure {
+            Some(on_task_failure) => self.handle_message(on_task_failure(failure)),
+            None => log::warn!("{failure}"),
+        }
     }
 
     fn handle_message(&mut self, message: Message) {
@@ -191,9 +893,10 @@ where
     Sub: IntoSubscription<Message> + Send + 'static,
 {
     fn drop(&mut self) {
-        if let Some(handle) = self.subscription_task.take() {
-            handle.abort();
+        for running in self.subscriptions.drain(..) {
+            running.handle.abort();
         }
+        self.tasks.abort_all();
     }
 }
 
@@ -203,15 +906,26 @@ where
     Message: Send + 'static,
     Sub: IntoSubscription<Message> + Send + 'static,
 {
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        while let Ok(message) = self.mailbox_receiver.try_recv() {
-            self.handle_message(message);
-        }
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        self.drain_mailbox();
+        self.dispatch_frame_tick(ctx);
+        self.apply_ui_actions(ctx, frame);
 
-        let view_context = ViewContext::new(self.mailbox_sender.clone());
+        let view_context = ViewContext::new(self.mailbox_sender.clone())
+            .with_pending_tasks(self.tasks.pending())
+            .with_repaint(ctx.clone());
         (self.program.view)(&self.model, ctx, &view_context);
 
-        ctx.request_repaint();
+        if self.needs_repaint() {
+            ctx.request_repaint();
+        }
+    }
+
+    fn raw_input_hook(&mut self, _ctx: &egui::Context, raw_input: &mut egui::RawInput) {
+        self.handle_paste_events(raw_input);
+        self.handle_screenshot_events(raw_input);
+        self.observe_system_theme(raw_input);
+        self.dispatch_input_events(raw_input);
     }
 
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
@@ -226,3 +940,350 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscription::Subscription;
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Default)]
+    struct Recorder {
+        received: Vec<u32>,
+    }
+
+    fn app_with_subscription(subscription: fn(&Recorder) -> Subscription<u32>) -> TestApp {
+        app_with(Program::new(
+            |_| (Recorder::default(), Command::none()),
+            |model, message| {
+                model.received.push(message);
+                Command::none()
+            },
+            |_, _, _| {},
+            subscription,
+        ))
+    }
+
+    type TestApp = ElmApp<Recorder, u32, Subscription<u32>>;
+
+    fn app_with(program: Program<Recorder, u32>) -> TestApp {
+        let runtime = TokioRuntime::Owned(Runtime::new().unwrap());
+        ElmApp::new(
+            program,
+            Recorder::default(),
+            Command::none(),
+            runtime,
+            egui::Context::default(),
+        )
+    }
+
+    fn app() -> TestApp {
+        app_with_subscription(|_| Subscription::none())
+    }
+
+    /// Repeats `step` until it returns `true`, giving the runtime up to two seconds.
+    fn eventually(app: &mut TestApp, mut step: impl FnMut(&mut TestApp) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !step(app) {
+            assert!(
+                Instant::now() < deadline,
+                "timed out waiting for the runtime"
+            );
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Emits `message` and then keeps running until aborted.
+    fn lingering(message: u32) -> Command<u32> {
+        Command::stream(futures::stream::iter([message]).chain(futures::stream::pending()))
+    }
+
+    #[test]
+    fn task_failure_renders_panic_payloads() {
+        let failure = TaskFailure::from_panic(
+            TaskOrigin::Command,
+            Some("fetch_user".into()),
+            Box::new(format!("index {} out of bounds", 3)),
+        );
+        assert_eq!(failure.message(), "index 3 out of bounds");
+        assert_eq!(
+            failure.to_string(),
+            "command `fetch_user` panicked: index 3 out of bounds"
+        );
+
+        let failure = TaskFailure::from_panic(TaskOrigin::Subscription, None, Box::new("boom"));
+        assert_eq!(failure.to_string(), "subscription panicked: boom");
+    }
+
+    #[test]
+    fn pending_lists_running_labeled_tasks() {
+        let runtime = Runtime::new().unwrap();
+        let mut tasks = TaskRegistry::default();
+        let key = CommandKey::new("search");
+        for (key, label) in [
+            (None, Some("fetch_user")),
+            (None, None),
+            (Some(key.clone()), Some("search")),
+        ] {
+            let id = tasks.next_id();
+            let handle = runtime.spawn(futures::future::pending());
+            tasks.insert(id, key, label.map(Cow::Borrowed), handle);
+        }
+
+        tasks.cancel(&key);
+
+        let pending = tasks.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].label(), "fetch_user");
+        tasks.abort_all();
+    }
+
+    #[test]
+    fn cancel_aborts_keyed_tasks_and_drops_their_queued_messages() {
+        let mut app = app();
+        app.enqueue_command(lingering(7).with_key("search"));
+        eventually(&mut app, |app| !app.mailbox_receiver.is_empty());
+
+        app.enqueue_command(Command::cancel("search"));
+        eventually(&mut app, |app| {
+            app.drain_mailbox();
+            app.tasks.tasks.is_empty()
+        });
+        assert!(app.model.received.is_empty());
+
+        // Once the aborted task is pruned, the key can be reused.
+        app.enqueue_command(Command::keyed("search", async { 8 }));
+        eventually(&mut app, |app| {
+            app.drain_mailbox();
+            !app.model.received.is_empty()
+        });
+        assert_eq!(app.model.received, [8]);
+    }
+
+    #[test]
+    fn latest_supersedes_earlier_tasks_but_not_its_own_batch() {
+        let mut app = app();
+        app.enqueue_command(lingering(1).latest_wins("search"));
+        eventually(&mut app, |app| !app.mailbox_receiver.is_empty());
+
+        app.enqueue_command(Command::latest("search", async { 2 }));
+        eventually(&mut app, |app| {
+            app.drain_mailbox();
+            app.tasks.tasks.is_empty()
+        });
+        assert_eq!(app.model.received, [2]);
+
+        app.enqueue_command(Command::batch([
+            Command::latest("search", async { 3 }),
+            Command::latest("search", async { 4 }),
+        ]));
+        eventually(&mut app, |app| {
+            app.drain_mailbox();
+            app.model.received.len() == 3
+        });
+        app.model.received.sort_unstable();
+        assert_eq!(app.model.received, [2, 3, 4]);
+    }
+
+    #[test]
+    fn groups_run_tasks_in_spawn_order_up_to_their_limit() {
+        let mut app = app_with(
+            Program::new(
+                |_| (Recorder::default(), Command::none()),
+                |model, message| {
+                    model.received.push(message);
+                    Command::none()
+                },
+                |_, _, _| {},
+                |_| Subscription::none(),
+            )
+            .with_concurrency_limit("downloads", 2),
+        );
+        let started = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        for index in 0..6 {
+            let (started, running, peak) = (started.clone(), running.clone(), peak.clone());
+            let download = Command::async_(async move {
+                started.lock().unwrap().push(index);
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                index
+            });
+            app.enqueue_command(download.in_group("downloads"));
+        }
+        eventually(&mut app, |app| {
+            app.drain_mailbox();
+            app.model.received.len() == 6
+        });
+
+        assert_eq!(*started.lock().unwrap(), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn restarting_keeps_matching_children_and_aborts_removed_ones() {
+        static STARTED: std::sync::Mutex<Vec<u32>> = std::sync::Mutex::new(Vec::new());
+        static STOPPED: std::sync::Mutex<Vec<u32>> = std::sync::Mutex::new(Vec::new());
+
+        struct Stop(u32);
+        impl Drop for Stop {
+            fn drop(&mut self) {
+                STOPPED.lock().unwrap().push(self.0);
+            }
+        }
+
+        fn child(id: u32) -> Subscription<u32> {
+            Subscription::from_stream(async_stream::stream! {
+                let _stop = Stop(id);
+                STARTED.lock().unwrap().push(id);
+                futures::future::pending::<()>().await;
+                yield id;
+            })
+            .with_token(id)
+        }
+
+        let mut app = app_with_subscription(|model| match model.received.last() {
+            Some(1) => child(1),
+            Some(2) => Subscription::batch([child(1), child(2)]),
+            Some(3) => child(2),
+            _ => Subscription::none(),
+        });
+
+        app.handle_message(1);
+        eventually(&mut app, |_| *STARTED.lock().unwrap() == [1]);
+
+        app.handle_message(2);
+        eventually(&mut app, |_| STARTED.lock().unwrap().len() == 2);
+        assert_eq!(*STARTED.lock().unwrap(), [1, 2]);
+
+        app.handle_message(3);
+        eventually(&mut app, |_| *STOPPED.lock().unwrap() == [1]);
+        assert_eq!(*STARTED.lock().unwrap(), [1, 2]);
+        assert_eq!(app.subscriptions.len(), 1);
+    }
+
+    #[test]
+    fn panicked_subscriptions_start_again_after_the_next_message() {
+        static STARTS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut app = app_with_subscription(|_| {
+            Subscription::from_stream(futures::stream::once(async {
+                STARTS.fetch_add(1, Ordering::SeqCst);
+                panic!("connection lost")
+            }))
+            .with_token("socket")
+        });
+        eventually(&mut app, |app| {
+            app.subscriptions[0].failed.load(Ordering::SeqCst)
+        });
+        assert_eq!(STARTS.load(Ordering::SeqCst), 1);
+
+        app.handle_message(1);
+        eventually(&mut app, |_| STARTS.load(Ordering::SeqCst) == 2);
+    }
+
+    #[test]
+    fn system_theme_reaches_listeners_that_appear_later() {
+        // Follows the system theme only after the first message.
+        let mut app = app_with_subscription(|model| {
+            if model.received.is_empty() {
+                Subscription::none()
+            } else {
+                Subscription::system_theme(|theme| match theme {
+                    egui::Theme::Dark => 100,
+                    egui::Theme::Light => 200,
+                })
+            }
+        });
+        let dark = egui::RawInput {
+            system_theme: Some(egui::Theme::Dark),
+            ..Default::default()
+        };
+
+        app.observe_system_theme(&dark);
+        app.handle_message(1);
+        assert!(app.needs_repaint());
+
+        app.observe_system_theme(&dark);
+        assert_eq!(app.model.received, [1, 100]);
+
+        // Later frames with the same theme stay quiet.
+        app.observe_system_theme(&dark);
+        assert_eq!(app.model.received, [1, 100]);
+    }
+
+    #[test]
+    fn clipboard_reads_wait_for_the_paste_event() {
+        let mut app = app();
+        app.clipboard_reads.push(Box::new(|text: String| {
+            CommandOutput::Message(text.len() as u32)
+        }));
+
+        // eframe answers the request only after painting the next frame.
+        app.handle_paste_events(&mut egui::RawInput::default());
+        assert!(app.model.received.is_empty());
+
+        let mut input = egui::RawInput {
+            events: vec![egui::Event::Paste("hello".into())],
+            ..Default::default()
+        };
+        app.handle_paste_events(&mut input);
+        assert_eq!(app.model.received, [5]);
+        assert!(input.events.is_empty());
+
+        // An empty clipboard sends no event at all.
+        app.clipboard_reads.push(Box::new(|text: String| {
+            CommandOutput::Message(text.len() as u32)
+        }));
+        for _ in 0..PASTE_TIMEOUT_FRAMES {
+            app.handle_paste_events(&mut egui::RawInput::default());
+        }
+        assert_eq!(app.model.received, [5, 0]);
+    }
+
+    #[test]
+    fn unanswered_screenshots_stop_repainting() {
+        let mut app = app();
+        app.screenshots.insert(
+            1,
+            (
+                0,
+                Box::new(|image: Arc<egui::ColorImage>| {
+                    CommandOutput::Message(image.width() as u32)
+                }),
+            ),
+        );
+        app.screenshots.insert(
+            2,
+            (
+                0,
+                Box::new(|image: Arc<egui::ColorImage>| {
+                    CommandOutput::Message(image.width() as u32)
+                }),
+            ),
+        );
+
+        let input = egui::RawInput {
+            events: vec![egui::Event::Screenshot {
+                viewport_id: egui::ViewportId::ROOT,
+                user_data: egui::UserData::new(ScreenshotRequest(1)),
+                image: Arc::new(egui::ColorImage::filled([3, 1], egui::Color32::BLACK)),
+            }],
+            ..Default::default()
+        };
+        app.handle_screenshot_events(&input);
+        assert_eq!(app.model.received, [3]);
+        assert!(app.needs_repaint());
+
+        // The second request is never answered, e.g. by a backend without screenshot support.
+        for _ in 1..SCREENSHOT_TIMEOUT_FRAMES {
+            app.handle_screenshot_events(&egui::RawInput::default());
+        }
+        assert!(!app.needs_repaint());
+        assert_eq!(app.model.received, [3]);
+    }
+}
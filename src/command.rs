@@ -1,25 +1,290 @@
-use std::future::Future;
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
 
-use futures::{future::BoxFuture, FutureExt};
+use async_stream::stream;
+use futures::{
+    future::{self, AbortHandle, AbortRegistration, BoxFuture, Either},
+    stream::{self, Abortable, BoxStream, SelectAll},
+    FutureExt, Stream, StreamExt,
+};
+use futures_timer::Delay;
+
+use crate::{
+    retry::RetryPolicy,
+    subscription::SubscriptionToken,
+    texture::{self, TextureError, TextureSource},
+};
 
 /// Represents asynchronous work to be performed by the Elm runtime.
 pub struct Command<Message>
 where
     Message: Send + 'static,
 {
-    tasks: Vec<CommandFuture<Message>>,
+    actions: Vec<Action<Message>>,
 }
 
 /// Boxed future used internally by commands.
 pub type CommandFuture<Message> = BoxFuture<'static, Option<Message>>;
 
+/// Boxed stream of outputs driven by a single command task.
+pub(crate) type CommandStream<Message> = BoxStream<'static, CommandOutput<Message>>;
+
+/// Item produced by a running command task.
+pub(crate) enum CommandOutput<Message>
+where
+    Message: Send + 'static,
+{
+    /// A message for `update`.
+    Message(Message),
+    /// A follow-up command the runtime should enqueue, e.g. a cancellation inside a sequence.
+    Command(Command<Message>),
+    /// An error left for the program-wide error handler.
+    Error(CommandError),
+}
+
+impl<Message> CommandOutput<Message>
+where
+    Message: Send + 'static,
+{
+    /// Maps the message with `f`, including the messages of a follow-up command.
+    fn map_with<Output>(self, f: &MapFn<Message, Output>) -> CommandOutput<Output>
+    where
+        Output: Send + 'static,
+    {
+        match self {
+            Self::Message(message) => CommandOutput::Message(f(message)),
+            Self::Command(command) => CommandOutput::Command(command.map_with(f.clone())),
+            Self::Error(error) => CommandOutput::Error(error),
+        }
+    }
+
+    /// Replaces the message with the command `f` returns for it, see [`Command::and_then`].
+    fn and_then_with(self, f: &AndThenFn<Message>) -> Self {
+        match self {
+            Self::Message(message) => Self::Command(f(message)),
+            Self::Command(command) => Self::Command(command.and_then_with(f.clone())),
+            Self::Error(error) => Self::Error(error),
+        }
+    }
+
+    /// Drops the output once the handle is aborted and ties follow-up commands to it.
+    fn abort_with(self, handle: &CommandHandle) -> Self {
+        match self {
+            _ if handle.is_aborted() => Self::Command(Command::none()),
+            Self::Command(command) => Self::Command(command.abort_with(handle)),
+            output => output,
+        }
+    }
+}
+
+/// Type-erased mapper used by [`Command::map`].
+type MapFn<Message, Output> = Arc<dyn Fn(Message) -> Output + Send + Sync>;
+
+/// Type-erased continuation used by [`Command::and_then`].
+type AndThenFn<Message> = Arc<dyn Fn(Message) -> Command<Message> + Send + Sync>;
+
+/// Single unit of work carried by a [`Command`].
+pub(crate) enum Action<Message>
+where
+    Message: Send + 'static,
+{
+    /// Spawns a task on the runtime.
+    Spawn(Task<Message>),
+    /// Aborts every in-flight task registered under the key.
+    Cancel(CommandKey),
+    /// Applied by the runtime on the UI thread before the next view.
+    Ui(UiAction<Message>),
+}
+
+/// Closure passed to [`Command::with_context`].
+pub(crate) type ContextFn<Message> =
+    Box<dyn FnOnce(&egui::Context) -> Option<CommandOutput<Message>> + Send>;
+
+/// Turns the integration's reply to a UI action into the command's output.
+pub(crate) type ReplyFn<T, Message> = Box<dyn FnOnce(T) -> CommandOutput<Message> + Send>;
+
+/// Closure that runs against the context and hands follow-up work back to the runtime.
+pub(crate) type EffectFn<Message> = Box<dyn FnOnce(&egui::Context) -> Command<Message> + Send>;
+
+/// Effect that needs the egui context or the eframe frame and therefore runs on the UI thread.
+#[cfg_attr(not(feature = "runtime"), allow(dead_code))]
+pub(crate) enum UiAction<Message>
+where
+    Message: Send + 'static,
+{
+    /// Forwarded to [`egui::Context::send_viewport_cmd`].
+    Viewport(egui::ViewportCommand),
+    /// Places the text on the system clipboard.
+    CopyText(String),
+    /// Requests the clipboard contents and turns them into a message.
+    ReadClipboard(ReplyFn<String, Message>),
+    /// Gives keyboard focus to the widget.
+    Focus(egui::Id),
+    /// Takes keyboard focus away from the widget if it has it.
+    SurrenderFocus(egui::Id),
+    /// Scrolls enclosing scroll areas so the widget becomes visible.
+    ScrollTo(egui::Id, Option<egui::Align>),
+    /// Captures the rendered window and turns the image into a message.
+    Screenshot(ReplyFn<Arc<egui::ColorImage>, Message>),
+    /// Runs arbitrary code against the context.
+    Context(ContextFn<Message>),
+    /// Runs code against the context and enqueues the command it returns.
+    Effect(EffectFn<Message>),
+    /// Writes a value to the app's persistent storage.
+    StorageSet(String, String),
+    /// Reads a value from the app's persistent storage and turns it into a message.
+    StorageGet(String, ReplyFn<Option<String>, Message>),
+    /// Writes the persistent storage to disk.
+    FlushStorage,
+}
+
+impl<Message> UiAction<Message>
+where
+    Message: Send + 'static,
+{
+    fn map_with<Output>(self, f: MapFn<Message, Output>) -> UiAction<Output>
+    where
+        Output: Send + 'static,
+    {
+        match self {
+            Self::Viewport(command) => UiAction::Viewport(command),
+            Self::CopyText(text) => UiAction::CopyText(text),
+            Self::ReadClipboard(read) => {
+                UiAction::ReadClipboard(Box::new(move |text| read(text).map_with(&f)))
+            }
+            Self::Focus(id) => UiAction::Focus(id),
+            Self::SurrenderFocus(id) => UiAction::SurrenderFocus(id),
+            Self::ScrollTo(id, align) => UiAction::ScrollTo(id, align),
+            Self::Screenshot(capture) => {
+                UiAction::Screenshot(Box::new(move |image| capture(image).map_with(&f)))
+            }
+            Self::Context(run) => UiAction::Context(Box::new(move |ctx| {
+                run(ctx).map(|output| output.map_with(&f))
+            })),
+            Self::Effect(run) => UiAction::Effect(Box::new(move |ctx| run(ctx).map_with(f))),
+            Self::StorageSet(key, value) => UiAction::StorageSet(key, value),
+            Self::StorageGet(key, read) => {
+                UiAction::StorageGet(key, Box::new(move |value| read(value).map_with(&f)))
+            }
+            Self::FlushStorage => UiAction::FlushStorage,
+        }
+    }
+
+    /// Chains `f` off the messages the action produces, see [`Command::and_then`].
+    fn and_then_with(self, f: &AndThenFn<Message>) -> Self {
+        let f = f.clone();
+        match self {
+            Self::ReadClipboard(read) => {
+                Self::ReadClipboard(Box::new(move |text| read(text).and_then_with(&f)))
+            }
+            Self::Screenshot(capture) => {
+                Self::Screenshot(Box::new(move |image| capture(image).and_then_with(&f)))
+            }
+            Self::Context(run) => Self::Context(Box::new(move |ctx| {
+                run(ctx).map(|output| output.and_then_with(&f))
+            })),
+            Self::Effect(run) => Self::Effect(Box::new(move |ctx| run(ctx).and_then_with(f))),
+            Self::StorageGet(key, read) => {
+                Self::StorageGet(key, Box::new(move |value| read(value).and_then_with(&f)))
+            }
+            action => action,
+        }
+    }
+
+    /// Drops the messages the action produces once the handle is aborted, see
+    /// [`Command::abortable`]. Closures that run against the context are skipped entirely.
+    fn abort_with(self, handle: &CommandHandle) -> Self {
+        let handle = handle.clone();
+        match self {
+            Self::ReadClipboard(read) => {
+                Self::ReadClipboard(Box::new(move |text| read(text).abort_with(&handle)))
+            }
+            Self::Screenshot(capture) => {
+                Self::Screenshot(Box::new(move |image| capture(image).abort_with(&handle)))
+            }
+            Self::Context(run) => Self::Context(Box::new(move |ctx| {
+                if handle.is_aborted() {
+                    return None;
+                }
+                run(ctx).map(|output| output.abort_with(&handle))
+            })),
+            Self::Effect(run) => Self::Effect(Box::new(move |ctx| {
+                if handle.is_aborted() {
+                    return Command::none();
+                }
+                run(ctx).abort_with(&handle)
+            })),
+            Self::StorageGet(key, read) => {
+                Self::StorageGet(key, Box::new(move |value| read(value).abort_with(&handle)))
+            }
+            action => action,
+        }
+    }
+}
+
+/// Stream together with the metadata the runtime uses to track it.
+pub(crate) struct Task<Message>
+where
+    Message: Send + 'static,
+{
+    pub(crate) stream: CommandStream<Message>,
+    pub(crate) key: Option<CommandKey>,
+    /// Whether spawning this task supersedes earlier tasks with the same key.
+    pub(crate) latest: bool,
+    /// Concurrency group the task belongs to, see [`Command::in_group`].
+    pub(crate) group: Option<Cow<'static, str>>,
+    /// Human readable name used when reporting the task, see [`Command::with_label`].
+    pub(crate) label: Option<Cow<'static, str>>,
+}
+
+impl<Message> Task<Message>
+where
+    Message: Send + 'static,
+{
+    fn new(stream: CommandStream<Message>) -> Self {
+        Self {
+            stream,
+            key: None,
+            latest: false,
+            group: None,
+            label: None,
+        }
+    }
+
+    /// Replaces the stream while keeping the metadata of the task.
+    fn map_stream<Output, F>(self, f: F) -> Task<Output>
+    where
+        Output: Send + 'static,
+        F: FnOnce(CommandStream<Message>) -> CommandStream<Output>,
+    {
+        Task {
+            stream: f(self.stream),
+            key: self.key,
+            latest: self.latest,
+            group: self.group,
+            label: self.label,
+        }
+    }
+}
+
 impl<Message> Command<Message>
 where
     Message: Send + 'static,
 {
     /// Creates a command that performs no work.
     pub fn none() -> Self {
-        Self { tasks: Vec::new() }
+        Self {
+            actions: Vec::new(),
+        }
     }
 
     /// Creates a command that immediately produces the provided message.
@@ -40,12 +305,111 @@ where
     where
         Fut: Future<Output = Option<Message>> + Send + 'static,
     {
-        Self {
-            tasks: vec![future.boxed()],
-        }
+        let future: CommandFuture<Message> = future.boxed();
+        Self::from_task(Task::new(
+            future
+                .into_stream()
+                .filter_map(|message| async move { message.map(CommandOutput::Message) })
+                .boxed(),
+        ))
+    }
+
+    /// Creates a command from a fallible future, mapping success and failure into messages.
+    pub fn try_async<Fut, T, E, OnOk, OnErr>(future: Fut, on_ok: OnOk, on_err: OnErr) -> Self
+    where
+        Fut: Future<Output = Result<T, E>> + Send + 'static,
+        OnOk: FnOnce(T) -> Message + Send + 'static,
+        OnErr: FnOnce(E) -> Message + Send + 'static,
+    {
+        Self::async_(async move {
+            match future.await {
+                Ok(value) => on_ok(value),
+                Err(error) => on_err(error),
+            }
+        })
+    }
+
+    /// Creates a command from a fallible future whose errors are not handled locally.
+    ///
+    /// Successful values are mapped into messages by `on_ok`. Errors are routed to the
+    /// handler registered with
+    /// [`Program::with_error_handler`](crate::program::Program::with_error_handler), which
+    /// keeps error reporting in one place instead of in every future.
+    pub fn fallible<Fut, T, E, OnOk>(future: Fut, on_ok: OnOk) -> Self
+    where
+        Fut: Future<Output = Result<T, E>> + Send + 'static,
+        E: Into<Box<dyn Error + Send + Sync>>,
+        OnOk: FnOnce(T) -> Message + Send + 'static,
+    {
+        Self::from_task(Task::new(
+            future
+                .map(|result| match result {
+                    Ok(value) => CommandOutput::Message(on_ok(value)),
+                    Err(error) => CommandOutput::Error(CommandError::new(error)),
+                })
+                .into_stream()
+                .boxed(),
+        ))
+    }
+
+    /// Creates a command that retries a fallible operation according to `policy`.
+    ///
+    /// `op` is called again after every error until it succeeds or the policy runs out of
+    /// attempts; the final result is then mapped into a message by `on_result`.
+    pub fn retry<Op, Fut, T, E, F>(policy: RetryPolicy, op: Op, on_result: F) -> Self
+    where
+        Op: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T, E>> + Send + 'static,
+        T: Send + 'static,
+        E: Send + 'static,
+        F: FnOnce(Result<T, E>) -> Message + Send + 'static,
+    {
+        Self::async_(async move { on_result(crate::retry::retry(policy, op).await) })
+    }
+
+    /// Creates a command that forwards every item of the stream to `update`.
+    ///
+    /// Useful for long jobs that report progress before delivering their final result.
+    pub fn stream<S>(stream: S) -> Self
+    where
+        S: Stream<Item = Message> + Send + 'static,
+    {
+        Self::from_task(Task::new(stream.map(CommandOutput::Message).boxed()))
+    }
+
+    /// Creates a command that runs the stream and maps each item into a message.
+    pub fn run<S, F>(stream: S, f: F) -> Self
+    where
+        S: Stream + Send + 'static,
+        F: FnMut(S::Item) -> Message + Send + 'static,
+    {
+        Self::stream(stream.map(f))
+    }
+
+    /// Creates a command that delivers the message after `duration`, e.g. to dismiss a toast.
+    ///
+    /// Uses the same timer as [`Subscription::interval`](crate::subscription::Subscription::interval).
+    /// Like any other command it can be keyed with [`Command::with_key`] and cancelled.
+    pub fn after(duration: Duration, message: Message) -> Self {
+        Self::async_(async move {
+            Delay::new(duration).await;
+            message
+        })
+    }
+
+    /// Creates a command that delivers the message at the given point in time, or right away
+    /// if it has already passed.
+    pub fn at(instant: Instant, message: Message) -> Self {
+        Self::async_(async move {
+            Delay::new(instant.saturating_duration_since(Instant::now())).await;
+            message
+        })
     }
 
     /// Creates a command from a synchronous computation.
+    ///
+    /// The closure runs directly on an async worker, so it should be cheap. Use
+    /// [`Command::blocking`] or [`Command::compute`] for heavy work.
     pub fn perform<F>(op: F) -> Self
     where
         F: FnOnce() -> Message + Send + 'static,
@@ -53,14 +417,464 @@ where
         Self::from_optional_future(async move { Some(op()) })
     }
 
+    /// Creates a command that runs blocking work, such as file or database access, on the
+    /// runtime's blocking thread pool instead of an async worker.
+    pub fn blocking<F>(op: F) -> Self
+    where
+        F: FnOnce() -> Message + Send + 'static,
+    {
+        Self::async_(async move { crate::pool::blocking(op).await })
+    }
+
+    /// Creates a command that runs CPU-bound work, such as image decoding or parsing, on a
+    /// dedicated pool with one thread per core.
+    ///
+    /// Unlike [`Command::blocking`] the pool never grows, so a burst of heavy jobs queues up
+    /// instead of oversubscribing the CPU.
+    pub fn compute<F>(op: F) -> Self
+    where
+        F: FnOnce() -> Message + Send + 'static,
+    {
+        Self::async_(async move { crate::pool::compute(op).await })
+    }
+
+    /// Creates a command from a future and names its task, see [`Command::with_label`].
+    pub fn labeled<Fut>(label: impl Into<Cow<'static, str>>, future: Fut) -> Self
+    where
+        Fut: Future<Output = Message> + Send + 'static,
+    {
+        Self::async_(future).with_label(label)
+    }
+
+    /// Creates a command from a future that the runtime tracks under `key`.
+    ///
+    /// The task can be aborted later with [`Command::cancel`] using an equal key.
+    pub fn keyed<K, Fut>(key: K, future: Fut) -> Self
+    where
+        K: PartialEq + Send + Sync + 'static,
+        Fut: Future<Output = Message> + Send + 'static,
+    {
+        Self::async_(future).with_key(key)
+    }
+
+    /// Creates a command from a future where only the most recent one per `key` wins.
+    ///
+    /// Issuing another latest-wins command under an equal key aborts this one and
+    /// suppresses its message, which keeps request/response flows such as typeahead
+    /// search from delivering results out of order.
+    pub fn latest<K, Fut>(key: K, future: Fut) -> Self
+    where
+        K: PartialEq + Send + Sync + 'static,
+        Fut: Future<Output = Message> + Send + 'static,
+    {
+        Self::async_(future).latest_wins(key)
+    }
+
+    /// Creates a command that aborts every in-flight task registered under `key`.
+    ///
+    /// Messages the aborted tasks have produced but `update` has not seen yet are dropped.
+    pub fn cancel<K>(key: K) -> Self
+    where
+        K: PartialEq + Send + Sync + 'static,
+    {
+        Self {
+            actions: vec![Action::Cancel(CommandKey::new(key))],
+        }
+    }
+
+    /// Creates a command that sends a [`egui::ViewportCommand`] to the root viewport.
+    ///
+    /// The runtime applies it on the next frame, before the view runs.
+    pub fn viewport(command: egui::ViewportCommand) -> Self {
+        Self::from_ui(UiAction::Viewport(command))
+    }
+
+    /// Creates a command that closes the window and, with it, the application.
+    pub fn close_window() -> Self {
+        Self::viewport(egui::ViewportCommand::Close)
+    }
+
+    /// Creates a command that changes the window title.
+    pub fn set_title(title: impl Into<String>) -> Self {
+        Self::viewport(egui::ViewportCommand::Title(title.into()))
+    }
+
+    /// Creates a command that resizes the inner area of the window, in points.
+    pub fn resize(size: impl Into<egui::Vec2>) -> Self {
+        Self::viewport(egui::ViewportCommand::InnerSize(size.into()))
+    }
+
+    /// Creates a command that enters or leaves fullscreen mode.
+    pub fn fullscreen(fullscreen: bool) -> Self {
+        Self::viewport(egui::ViewportCommand::Fullscreen(fullscreen))
+    }
+
+    /// Creates a command that maximizes or restores the window.
+    pub fn maximize(maximized: bool) -> Self {
+        Self::viewport(egui::ViewportCommand::Maximized(maximized))
+    }
+
+    /// Creates a command that minimizes the window.
+    pub fn minimize() -> Self {
+        Self::viewport(egui::ViewportCommand::Minimized(true))
+    }
+
+    /// Creates a command that places the text on the system clipboard.
+    pub fn copy_to_clipboard(text: impl Into<String>) -> Self {
+        Self::from_ui(UiAction::CopyText(text.into()))
+    }
+
+    /// Creates a command that reads the system clipboard and maps its text into a message.
+    ///
+    /// The text arrives one frame later; an empty or non-text clipboard yields an empty
+    /// string.
+    pub fn read_clipboard<F>(f: F) -> Self
+    where
+        F: FnOnce(String) -> Message + Send + 'static,
+    {
+        Self::from_ui(UiAction::ReadClipboard(Box::new(move |text| {
+            CommandOutput::Message(f(text))
+        })))
+    }
+
+    /// Creates a command that moves keyboard focus to the widget with the given id, e.g. the
+    /// field that failed validation.
+    ///
+    /// Use the same id the view gives the widget, for instance via
+    /// [`egui::TextEdit::id`].
+    pub fn focus(id: egui::Id) -> Self {
+        Self::from_ui(UiAction::Focus(id))
+    }
+
+    /// Creates a command that removes keyboard focus from the widget if it has it.
+    pub fn surrender_focus(id: egui::Id) -> Self {
+        Self::from_ui(UiAction::SurrenderFocus(id))
+    }
+
+    /// Creates a command that scrolls the widget with the given id into view.
+    ///
+    /// The widget must have been shown in the previous frame.
+    pub fn scroll_to(id: egui::Id) -> Self {
+        Self::from_ui(UiAction::ScrollTo(id, None))
+    }
+
+    /// Like [`Command::scroll_to`], but aligns the widget within the visible area.
+    pub fn scroll_to_aligned(id: egui::Id, align: egui::Align) -> Self {
+        Self::from_ui(UiAction::ScrollTo(id, Some(align)))
+    }
+
+    /// Creates a command that takes a screenshot of the window and maps it into a message.
+    ///
+    /// The image shows the frame rendered right after the command was applied. If the
+    /// integration does not deliver it within a few frames, e.g. because the rendering backend
+    /// cannot take screenshots, the request is dropped and no message is produced.
+    pub fn screenshot<F>(f: F) -> Self
+    where
+        F: FnOnce(Arc<egui::ColorImage>) -> Message + Send + 'static,
+    {
+        Self::from_ui(UiAction::Screenshot(Box::new(move |image| {
+            CommandOutput::Message(f(image))
+        })))
+    }
+
+    /// Creates a command that runs on the UI thread with access to the [`egui::Context`].
+    ///
+    /// Use it for effects that need the context outside the view, such as loading textures,
+    /// registering fonts, changing the style or storing data in `ctx.memory`. The closure runs
+    /// before the next view; a returned message is passed to `update` right away.
+    pub fn with_context<F>(f: F) -> Self
+    where
+        F: FnOnce(&egui::Context) -> Option<Message> + Send + 'static,
+    {
+        Self::from_ui(UiAction::Context(Box::new(move |ctx| {
+            f(ctx).map(CommandOutput::Message)
+        })))
+    }
+
+    /// Creates a command that loads a texture and caches its handle under `name`.
+    ///
+    /// The source is read and decoded on the blocking pool and uploaded on the UI thread.
+    /// Later loads with the same name reuse the cached handle without decoding again, so the
+    /// command can be issued whenever the texture is needed. Decoding encoded bytes or files
+    /// requires the `image` feature.
+    ///
+    /// Keys, groups and labels set on the command apply to the decoding task, so
+    /// `.in_group("thumbnails")` limits how many images decode at once.
+    pub fn load_texture<F>(
+        name: impl Into<String>,
+        source: impl Into<TextureSource>,
+        options: egui::TextureOptions,
+        f: F,
+    ) -> Self
+    where
+        F: FnOnce(Result<egui::TextureHandle, TextureError>) -> Message + Send + 'static,
+    {
+        let name = name.into();
+        let source = source.into();
+        Self::from_ui(UiAction::Effect(Box::new(move |ctx| {
+            if let Some(handle) = texture::cached(ctx, &name) {
+                return Self::message(f(Ok(handle)));
+            }
+
+            Self::from_task(Task::new(
+                stream::once(async move {
+                    let decoded = crate::pool::blocking(move || source.decode()).await;
+                    CommandOutput::Command(Self::with_context(move |ctx| {
+                        Some(f(
+                            decoded.map(|image| texture::upload(ctx, name, image, options))
+                        ))
+                    }))
+                })
+                .boxed(),
+            ))
+        })))
+    }
+
+    /// Creates a command that drops the handle cached by [`Command::load_texture`].
+    ///
+    /// The texture is freed once no other handle refers to it.
+    pub fn forget_texture(name: impl Into<String>) -> Self {
+        let name = name.into();
+        Self::with_context(move |ctx| {
+            texture::forget(ctx, &name);
+            None
+        })
+    }
+
+    /// Creates a command that stores `value` under `key` in the app's persistent storage.
+    ///
+    /// Storage is only available with the `persistence` feature; without it the command does
+    /// nothing. The value is written to disk at eframe's next autosave, on exit or when
+    /// [`Command::flush_storage`] runs.
+    pub fn storage_set(key: impl Into<String>, value: impl Into<String>) -> Self {
+        Self::from_ui(UiAction::StorageSet(key.into(), value.into()))
+    }
+
+    /// Creates a command that reads the value stored under `key` and turns it into a message.
+    ///
+    /// The closure receives `None` when the key is missing or storage is unavailable.
+    pub fn storage_get<F>(key: impl Into<String>, f: F) -> Self
+    where
+        F: FnOnce(Option<String>) -> Message + Send + 'static,
+    {
+        Self::from_ui(UiAction::StorageGet(
+            key.into(),
+            Box::new(move |value| CommandOutput::Message(f(value))),
+        ))
+    }
+
+    /// Creates a command that writes the persistent storage to disk right away.
+    pub fn flush_storage() -> Self {
+        Self::from_ui(UiAction::FlushStorage)
+    }
+
+    /// Creates a command that switches between the dark, light or system theme.
+    pub fn set_theme(theme: egui::ThemePreference) -> Self {
+        Self::with_context(move |ctx| {
+            ctx.set_theme(theme);
+            None
+        })
+    }
+
+    /// Creates a command that replaces the visuals of the currently active theme.
+    pub fn set_visuals(visuals: egui::Visuals) -> Self {
+        Self::with_context(move |ctx| {
+            ctx.set_visuals(visuals);
+            None
+        })
+    }
+
     /// Batches multiple commands together so they can run in parallel.
     pub fn batch(commands: impl IntoIterator<Item = Self>) -> Self {
-        let tasks = commands
+        let actions = commands
             .into_iter()
-            .flat_map(|command| command.tasks)
+            .flat_map(|command| command.actions)
             .collect();
 
-        Self { tasks }
+        Self { actions }
+    }
+
+    /// Runs commands one after another, delivering their messages in order.
+    ///
+    /// Each command starts once every task of the previous one has finished. The whole
+    /// sequence runs as a single task, so keys set on the inner commands are ignored; key
+    /// the sequence itself to cancel it.
+    pub fn sequence(commands: impl IntoIterator<Item = Self>) -> Self {
+        let commands: Vec<_> = commands.into_iter().collect();
+        Self::from_task(Task::new(
+            stream::iter(commands)
+                .flat_map(Command::into_stream)
+                .boxed(),
+        ))
+    }
+
+    /// Chains a follow-up command off every message this command produces.
+    ///
+    /// The messages themselves do not reach `update`; `f` turns each of them into the next
+    /// command, which runs as part of the same task. Messages of effects on the UI thread,
+    /// such as [`Command::read_clipboard`] or [`Command::with_context`], are chained too; their
+    /// follow-up command is enqueued once the effect has run.
+    pub fn and_then<F>(self, f: F) -> Self
+    where
+        F: Fn(Message) -> Command<Message> + Send + Sync + 'static,
+    {
+        self.and_then_with(Arc::new(f))
+    }
+
+    /// Type-erased implementation of [`Command::and_then`], see [`Command::map_with`].
+    fn and_then_with(self, f: AndThenFn<Message>) -> Self {
+        let actions =
+            self.actions
+                .into_iter()
+                .map(|action| match action {
+                    Action::Spawn(task) => {
+                        let f = f.clone();
+                        Action::Spawn(task.map_stream(|stream| {
+                            stream
+                                .flat_map(move |output| match output {
+                                    CommandOutput::Message(message) => f(message).into_stream(),
+                                    output => stream::once(future::ready(output.and_then_with(&f)))
+                                        .boxed(),
+                                })
+                                .boxed()
+                        }))
+                    }
+                    Action::Cancel(key) => Action::Cancel(key),
+                    Action::Ui(action) => Action::Ui(action.and_then_with(&f)),
+                })
+                .collect();
+
+        Self { actions }
+    }
+
+    /// Bounds how long the command may run.
+    ///
+    /// Messages produced within `duration` are delivered as usual. If the command is still
+    /// running when the time is up it is dropped and `on_timeout` is delivered instead. The
+    /// timer starts when the command starts running, and the result runs as a single task.
+    ///
+    /// The key, label and group carry over when every task of the command shares them. If
+    /// the tasks disagree they are dropped; key, label or group the result itself instead.
+    ///
+    /// Only tasks are bounded. Effects on the UI thread, such as [`Command::read_clipboard`],
+    /// [`Command::screenshot`] or [`Command::load_texture`], are handed to the runtime right
+    /// away and deliver their message whenever it arrives.
+    pub fn timeout(self, duration: Duration, on_timeout: Message) -> Self {
+        self.race(move || Delay::new(duration), on_timeout)
+    }
+
+    /// Like [`Command::timeout`], but gives up at a fixed point in time.
+    pub fn deadline(self, deadline: Instant, on_deadline: Message) -> Self {
+        self.race(
+            move || Delay::new(deadline.saturating_duration_since(Instant::now())),
+            on_deadline,
+        )
+    }
+
+    /// Registers every task of this command under `key` so it can be cancelled.
+    pub fn with_key<K>(self, key: K) -> Self
+    where
+        K: PartialEq + Send + Sync + 'static,
+    {
+        let key = CommandKey::new(key);
+        self.map_tasks(move |mut task| {
+            task.key = Some(key.clone());
+            task
+        })
+    }
+
+    /// Registers every task of this command under `key` with latest-wins semantics.
+    ///
+    /// When the command is enqueued, in-flight tasks with an equal key are aborted first and
+    /// their pending messages are dropped. See [`Command::latest`].
+    pub fn latest_wins<K>(self, key: K) -> Self
+    where
+        K: PartialEq + Send + Sync + 'static,
+    {
+        let key = CommandKey::new(key);
+        self.map_tasks(move |mut task| {
+            task.key = Some(key.clone());
+            task.latest = true;
+            task
+        })
+    }
+
+    /// Puts every task of this command into the named concurrency group.
+    ///
+    /// The runtime runs at most as many tasks of a group at once as configured with
+    /// [`Program::with_concurrency_limit`](crate::program::Program::with_concurrency_limit);
+    /// the rest wait in line. Groups without a configured limit are unbounded.
+    pub fn in_group(self, group: impl Into<Cow<'static, str>>) -> Self {
+        let group = group.into();
+        self.map_tasks(move |mut task| {
+            task.group = Some(group.clone());
+            task
+        })
+    }
+
+    /// Names every task of this command, e.g. `"fetch_user"`, for error reports and the
+    /// runtime's list of pending tasks.
+    pub fn with_label(self, label: impl Into<Cow<'static, str>>) -> Self {
+        let label = label.into();
+        self.map_tasks(move |mut task| {
+            task.label = Some(label.clone());
+            task
+        })
+    }
+
+    /// Makes the command abortable and returns a handle that can stop it from anywhere.
+    ///
+    /// Unlike [`Command::cancel`] the handle does not go through the runtime, so it can be
+    /// stored in the model and aborted directly, e.g. when a screen is torn down. Messages of
+    /// effects on the UI thread and follow-up commands are dropped as well.
+    pub fn abortable(self) -> (Self, CommandHandle) {
+        let handle = CommandHandle {
+            handles: Arc::default(),
+            aborted: Arc::default(),
+        };
+        (self.abort_with(&handle), handle)
+    }
+
+    /// Ties every task and effect of the command to the handle, see [`Command::abortable`].
+    fn abort_with(self, handle: &CommandHandle) -> Self {
+        let actions = self
+            .actions
+            .into_iter()
+            .map(|action| match action {
+                Action::Spawn(task) => {
+                    let registration = handle.register();
+                    let handle = handle.clone();
+                    Action::Spawn(task.map_stream(|stream| {
+                        Abortable::new(stream, registration)
+                            .map(move |output| output.abort_with(&handle))
+                            .boxed()
+                    }))
+                }
+                Action::Cancel(key) => Action::Cancel(key),
+                Action::Ui(action) => Action::Ui(action.abort_with(handle)),
+            })
+            .collect();
+
+        Self { actions }
+    }
+
+    /// Returns the number of tasks and effects carried by the command.
+    #[allow(clippy::len_without_is_empty)] // `is_none` mirrors `Command::none`.
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// Returns `true` if the command does nothing, like [`Command::none`].
+    pub fn is_none(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Returns the labels of the command's tasks, see [`Command::with_label`].
+    pub fn labels(&self) -> impl Iterator<Item = &str> + '_ {
+        self.actions.iter().filter_map(|action| match action {
+            Action::Spawn(task) => task.label.as_deref(),
+            _ => None,
+        })
     }
 
     /// Transforms the message type produced by the command.
@@ -69,23 +883,142 @@ where
         Output: Send + 'static,
         F: Fn(Message) -> Output + Send + Sync + 'static,
     {
-        let f = std::sync::Arc::new(f);
-        let tasks = self
-            .tasks
+        self.map_with(Arc::new(f))
+    }
+
+    /// Type-erased implementation of [`Command::map`], so follow-up commands can be mapped
+    /// recursively without instantiating a new closure type at every level.
+    fn map_with<Output>(self, f: MapFn<Message, Output>) -> Command<Output>
+    where
+        Output: Send + 'static,
+    {
+        let actions =
+            self.actions
+                .into_iter()
+                .map(|action| match action {
+                    Action::Spawn(task) => {
+                        let f = f.clone();
+                        Action::Spawn(task.map_stream(|stream| {
+                            stream.map(move |output| output.map_with(&f)).boxed()
+                        }))
+                    }
+                    Action::Cancel(key) => Action::Cancel(key),
+                    Action::Ui(action) => Action::Ui(action.map_with(f.clone())),
+                })
+                .collect();
+
+        Command { actions }
+    }
+
+    fn race<T>(self, timer: T, on_expired: Message) -> Self
+    where
+        T: FnOnce() -> Delay + Send + 'static,
+    {
+        let task = self
+            .shared_metadata()
+            .unwrap_or_else(|| Task::new(stream::empty().boxed()));
+        let mut outputs = self.into_stream();
+        Self::from_task(task.map_stream(|_| {
+            stream! {
+                let mut timer = timer();
+                loop {
+                    match future::select(outputs.next(), &mut timer).await {
+                        Either::Left((Some(output), _)) => yield output,
+                        Either::Left((None, _)) => break,
+                        Either::Right(_) => {
+                            yield CommandOutput::Message(on_expired);
+                            break;
+                        }
+                    }
+                }
+            }
+            .boxed()
+        }))
+    }
+
+    /// Returns an empty task carrying the key, label and group that every task of the
+    /// command shares, or `None` if there are no tasks or they disagree.
+    fn shared_metadata(&self) -> Option<Task<Message>> {
+        let mut tasks = self.actions.iter().filter_map(|action| match action {
+            Action::Spawn(task) => Some(task),
+            _ => None,
+        });
+        let first = tasks.next()?;
+        let shared = tasks.all(|task| {
+            task.key == first.key
+                && task.latest == first.latest
+                && task.group == first.group
+                && task.label == first.label
+        });
+
+        shared.then(|| Task {
+            stream: stream::empty().boxed(),
+            key: first.key.clone(),
+            latest: first.latest,
+            group: first.group.clone(),
+            label: first.label.clone(),
+        })
+    }
+
+    fn from_task(task: Task<Message>) -> Self {
+        Self {
+            actions: vec![Action::Spawn(task)],
+        }
+    }
+
+    fn from_ui(action: UiAction<Message>) -> Self {
+        Self {
+            actions: vec![Action::Ui(action)],
+        }
+    }
+
+    /// Applies `f` to every task, including the tasks effects hand back once they have run.
+    fn map_tasks<F>(self, f: F) -> Self
+    where
+        F: Fn(Task<Message>) -> Task<Message> + Clone + Send + 'static,
+    {
+        let actions = self
+            .actions
             .into_iter()
-            .map(|task| {
-                let f = f.clone();
-                task.map(move |maybe_message| maybe_message.map(|message| f(message)))
-                    .boxed()
+            .map(|action| match action {
+                Action::Spawn(task) => Action::Spawn(f(task)),
+                Action::Ui(UiAction::Effect(run)) => {
+                    let f = f.clone();
+                    Action::Ui(UiAction::Effect(Box::new(move |ctx| run(ctx).map_tasks(f))))
+                }
+                action => action,
             })
             .collect();
 
-        Command { tasks }
+        Self { actions }
+    }
+
+    /// Flattens the command into a single stream.
+    ///
+    /// Actions that are not tasks are emitted first as a follow-up command, then the outputs
+    /// of all tasks are merged.
+    fn into_stream(self) -> CommandStream<Message> {
+        let mut tasks = SelectAll::new();
+        let mut rest = Vec::new();
+        for action in self.actions {
+            match action {
+                Action::Spawn(task) => tasks.push(task.stream),
+                action => rest.push(action),
+            }
+        }
+
+        if rest.is_empty() {
+            tasks.boxed()
+        } else {
+            stream::once(async move { CommandOutput::Command(Command { actions: rest }) })
+                .chain(tasks)
+                .boxed()
+        }
     }
 
     #[cfg_attr(not(feature = "runtime"), allow(dead_code))]
-    pub(crate) fn into_futures(self) -> Vec<CommandFuture<Message>> {
-        self.tasks
+    pub(crate) fn into_actions(self) -> Vec<Action<Message>> {
+        self.actions
     }
 }
 
@@ -98,16 +1031,133 @@ where
     }
 }
 
+/// Identifier used to track and cancel in-flight commands.
+#[derive(Clone, PartialEq, Eq)]
+pub struct CommandKey(SubscriptionToken);
+
+impl CommandKey {
+    /// Creates a key from any comparable value.
+    pub fn new<T>(value: T) -> Self
+    where
+        T: PartialEq + Send + Sync + 'static,
+    {
+        Self(SubscriptionToken::new(value))
+    }
+}
+
+/// Error produced by a [`Command::fallible`] command that was not handled locally.
+pub struct CommandError {
+    inner: Box<dyn Error + Send + Sync>,
+}
+
+impl CommandError {
+    /// Wraps any error type.
+    pub fn new(error: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self {
+            inner: error.into(),
+        }
+    }
+
+    /// Returns the underlying error if it is of type `E`.
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: Error + 'static,
+    {
+        self.inner.downcast_ref()
+    }
+
+    /// Consumes the wrapper and returns the underlying error.
+    pub fn into_inner(self) -> Box<dyn Error + Send + Sync> {
+        self.inner
+    }
+}
+
+impl fmt::Debug for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl Error for CommandError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.inner.source()
+    }
+}
+
+/// Handle returned by [`Command::abortable`] that stops the command when aborted.
+#[derive(Clone)]
+pub struct CommandHandle {
+    /// Handles of the tasks started so far, including those of follow-up commands.
+    handles: Arc<Mutex<Vec<AbortHandle>>>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl CommandHandle {
+    /// Aborts the command. Tasks that have not produced their message yet never will.
+    pub fn abort(&self) {
+        let mut handles = self.handles.lock().unwrap_or_else(PoisonError::into_inner);
+        self.aborted.store(true, Ordering::Relaxed);
+        for handle in handles.drain(..) {
+            handle.abort();
+        }
+    }
+
+    /// Registers another task; it starts out aborted if the command already is.
+    fn register(&self) -> AbortRegistration {
+        let (handle, registration) = AbortHandle::new_pair();
+        let mut handles = self.handles.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.is_aborted() {
+            handle.abort();
+        } else {
+            handles.push(handle);
+        }
+        registration
+    }
+
+    /// Returns `true` once [`CommandHandle::abort`] has been called.
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use futures::executor::block_on;
 
+    /// Runs every task of the command to completion and returns the messages in order.
+    fn messages<Message: Send + 'static>(command: Command<Message>) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for action in command.into_actions() {
+            if let Action::Spawn(task) = action {
+                for output in block_on(task.stream.collect::<Vec<_>>()) {
+                    if let CommandOutput::Message(message) = output {
+                        messages.push(message);
+                    }
+                }
+            }
+        }
+        messages
+    }
+
+    /// Resolves the output of a UI action into the messages it leads to.
+    fn resolve<Message: Send + 'static>(output: CommandOutput<Message>) -> Vec<Message> {
+        match output {
+            CommandOutput::Message(message) => vec![message],
+            CommandOutput::Command(command) => messages(command),
+            CommandOutput::Error(error) => panic!("unexpected error: {error}"),
+        }
+    }
+
     #[test]
     fn message_command_completes() {
-        let mut futures = Command::message(5).into_futures();
-        let output = block_on(futures.pop().expect("future")).expect("message");
-        assert_eq!(output, 5);
+        assert_eq!(messages(Command::message(5)), vec![5]);
     }
 
     #[test]
@@ -115,13 +1165,473 @@ mod tests {
         let a = Command::message("a");
         let b = Command::message("b");
         let combined = Command::batch([a, b]);
-        let mut futures = combined.into_futures();
 
-        let mut results = Vec::new();
-        for future in futures.drain(..) {
-            results.push(block_on(future).unwrap());
-        }
+        let mut results = messages(combined);
         results.sort();
         assert_eq!(results, vec!["a", "b"]);
     }
+
+    #[test]
+    fn keyed_commands_carry_their_key() {
+        let command = Command::batch([
+            Command::keyed("search", async { 1 }),
+            Command::cancel("search"),
+        ]);
+        let actions = command.into_actions();
+
+        assert!(matches!(
+            &actions[0],
+            Action::Spawn(Task { key: Some(key), .. }) if *key == CommandKey::new("search")
+        ));
+        assert!(matches!(
+            &actions[1],
+            Action::Cancel(key) if *key == CommandKey::new("search")
+        ));
+    }
+
+    #[test]
+    fn latest_wins_marks_every_task() {
+        let command = Command::batch([Command::message(1), Command::message(2)])
+            .latest_wins("typeahead")
+            .map(|value| value * 10);
+
+        for action in command.into_actions() {
+            assert!(matches!(
+                action,
+                Action::Spawn(Task { key: Some(key), latest: true, .. })
+                    if key == CommandKey::new("typeahead")
+            ));
+        }
+    }
+
+    #[test]
+    fn in_group_tags_every_task() {
+        let command = Command::batch([Command::message(1), Command::cancel("other")])
+            .in_group("thumbnails")
+            .map(|value| value + 1);
+        let actions = command.into_actions();
+
+        assert!(matches!(
+            &actions[0],
+            Action::Spawn(Task { group: Some(group), .. }) if group == "thumbnails"
+        ));
+        assert!(matches!(&actions[1], Action::Cancel(_)));
+    }
+
+    #[test]
+    fn introspection_counts_actions_and_lists_labels() {
+        let command: Command<u32> = Command::batch([
+            Command::labeled("fetch_user", async { 1 }),
+            Command::message(2),
+            Command::labeled("fetch_posts", async { 3 }),
+            Command::close_window(),
+        ]);
+
+        assert_eq!(command.len(), 4);
+        assert!(!command.is_none());
+        assert!(Command::<u32>::none().is_none());
+        assert_eq!(
+            command.labels().collect::<Vec<_>>(),
+            ["fetch_user", "fetch_posts"]
+        );
+    }
+
+    #[test]
+    fn with_label_names_every_task() {
+        let command = Command::batch([Command::message(1), Command::message(2)])
+            .with_label("fetch_user")
+            .map(|value| value + 1);
+
+        for action in command.into_actions() {
+            assert!(matches!(
+                action,
+                Action::Spawn(Task { label: Some(label), .. }) if label == "fetch_user"
+            ));
+        }
+    }
+
+    #[test]
+    fn aborted_command_yields_nothing() {
+        let (command, handle) = Command::message(1).abortable();
+        handle.abort();
+
+        assert!(handle.is_aborted());
+        assert!(messages(command).is_empty());
+    }
+
+    #[test]
+    fn handles_without_tasks_start_out_running() {
+        let (_, handle) = Command::<()>::close_window().abortable();
+        assert!(!handle.is_aborted());
+
+        handle.abort();
+        assert!(handle.is_aborted());
+    }
+
+    #[test]
+    fn stream_forwards_every_item() {
+        let command = Command::run(stream::iter(1..=3), |progress| progress * 10);
+
+        assert_eq!(messages(command), vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn blocking_and_compute_run_off_the_calling_thread() {
+        let caller = std::thread::current().id();
+        let command = Command::batch([
+            Command::blocking(move || std::thread::current().id() != caller),
+            Command::compute(move || std::thread::current().id() != caller),
+        ]);
+
+        assert_eq!(messages(command), vec![true, true]);
+    }
+
+    #[test]
+    fn after_and_at_deliver_their_message() {
+        let started = Instant::now();
+        let command = Command::sequence([
+            Command::after(Duration::from_millis(20), "after"),
+            Command::at(Instant::now() + Duration::from_millis(10), "at"),
+        ]);
+
+        assert_eq!(messages(command), vec!["after", "at"]);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn sequence_delivers_messages_in_order() {
+        let command = Command::sequence([
+            Command::async_(async {
+                Delay::new(Duration::from_millis(10)).await;
+                1
+            }),
+            Command::message(2),
+            Command::batch([Command::message(3), Command::none()]),
+        ]);
+
+        assert_eq!(messages(command), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn timeout_delivers_fallback_when_time_runs_out() {
+        let command = Command::batch([
+            Command::message("early"),
+            Command::from_optional_future(future::pending()),
+        ])
+        .timeout(Duration::from_millis(10), "timed out");
+
+        assert_eq!(messages(command), vec!["early", "timed out"]);
+    }
+
+    #[test]
+    fn timeout_passes_through_fast_commands() {
+        let command = Command::message(1).timeout(Duration::from_secs(60), 0);
+
+        assert_eq!(messages(command), vec![1]);
+    }
+
+    #[test]
+    fn timeout_keeps_metadata_shared_by_all_tasks() {
+        let command = Command::batch([Command::message(1), Command::message(2)])
+            .in_group("search")
+            .with_label("search")
+            .latest_wins("search")
+            .timeout(Duration::from_secs(60), 0);
+        let Some(Action::Spawn(task)) = command.into_actions().pop() else {
+            panic!("timeout should spawn a single task");
+        };
+        assert!(task.key == Some(CommandKey::new("search")));
+        assert!(task.latest);
+        assert_eq!(task.group.as_deref(), Some("search"));
+        assert_eq!(task.label.as_deref(), Some("search"));
+
+        let command = Command::batch([
+            Command::keyed("first", async { 1 }),
+            Command::keyed("second", async { 2 }),
+        ])
+        .timeout(Duration::from_secs(60), 0);
+        let Some(Action::Spawn(task)) = command.into_actions().pop() else {
+            panic!("timeout should spawn a single task");
+        };
+        assert!(task.key.is_none());
+    }
+
+    #[test]
+    fn deadline_in_the_past_expires_immediately() {
+        let command = Command::from_optional_future(future::pending())
+            .deadline(Instant::now() - Duration::from_millis(1), "expired");
+
+        assert_eq!(messages(command), vec!["expired"]);
+    }
+
+    #[test]
+    fn retry_delivers_first_success() {
+        let mut failures = 2;
+        let command = Command::retry(
+            RetryPolicy::fixed(Duration::ZERO).max_attempts(5),
+            move || {
+                let result = if failures > 0 { Err("flaky") } else { Ok(42) };
+                failures -= 1;
+                async move { result }
+            },
+            |result| result.map_err(str::to_owned),
+        )
+        .map(|result| result.map(|value| value + 1));
+
+        assert_eq!(messages(command), vec![Ok(43)]);
+    }
+
+    #[test]
+    fn try_async_maps_both_outcomes() {
+        let command = Command::batch([
+            Command::try_async(async { Ok::<_, String>(1) }, |value| value, |_| 0),
+            Command::try_async(async { Err::<i32, _>("boom") }, |value| value, |_| -1),
+        ]);
+
+        let mut results = messages(command);
+        results.sort();
+        assert_eq!(results, vec![-1, 1]);
+    }
+
+    #[test]
+    fn fallible_routes_errors_to_the_runtime() {
+        let command =
+            Command::fallible(async { "not a number".parse::<i32>() }, |value: i32| value)
+                .map(|value| value * 2);
+        let Some(Action::Spawn(task)) = command.into_actions().pop() else {
+            panic!("fallible should spawn a single task");
+        };
+        let outputs = block_on(task.stream.collect::<Vec<_>>());
+
+        assert!(matches!(
+            &outputs[..],
+            [CommandOutput::Error(error)] if error.downcast_ref::<std::num::ParseIntError>().is_some()
+        ));
+    }
+
+    #[test]
+    fn sequence_forwards_cancellations_as_follow_up_commands() {
+        let command = Command::sequence([Command::message(1), Command::cancel("upload")]);
+        let Some(Action::Spawn(task)) = command.into_actions().pop() else {
+            panic!("sequence should spawn a single task");
+        };
+        let outputs = block_on(task.stream.collect::<Vec<_>>());
+
+        assert!(matches!(outputs[0], CommandOutput::Message(1)));
+        assert!(
+            matches!(&outputs[1], CommandOutput::Command(command) if command.actions.len() == 1)
+        );
+    }
+
+    #[test]
+    fn window_commands_become_viewport_actions() {
+        let command: Command<()> = Command::batch([
+            Command::set_title("Saved"),
+            Command::resize([640.0, 480.0]),
+            Command::close_window(),
+        ]);
+        let commands: Vec<_> = command
+            .into_actions()
+            .into_iter()
+            .map(|action| match action {
+                Action::Ui(UiAction::Viewport(command)) => command,
+                _ => panic!("expected a viewport action"),
+            })
+            .collect();
+
+        assert_eq!(
+            commands,
+            vec![
+                egui::ViewportCommand::Title("Saved".to_owned()),
+                egui::ViewportCommand::InnerSize(egui::vec2(640.0, 480.0)),
+                egui::ViewportCommand::Close,
+            ]
+        );
+    }
+
+    #[test]
+    fn read_clipboard_callbacks_are_mapped() {
+        let command = Command::read_clipboard(|text| text.len()).map(|len| len * 2);
+        let Some(Action::Ui(UiAction::ReadClipboard(read))) = command.into_actions().pop() else {
+            panic!("expected a clipboard read");
+        };
+
+        assert_eq!(resolve(read("abc".to_owned())), [6]);
+    }
+
+    #[test]
+    fn screenshot_callbacks_are_mapped() {
+        let command = Command::screenshot(|image| image.size).map(|[width, _]| width);
+        let Some(Action::Ui(UiAction::Screenshot(capture))) = command.into_actions().pop() else {
+            panic!("expected a screenshot");
+        };
+
+        let image = egui::ColorImage::filled([4, 2], egui::Color32::BLACK);
+        assert_eq!(resolve(capture(Arc::new(image))), [4]);
+    }
+
+    #[test]
+    fn with_context_runs_against_the_given_context() {
+        let command = Command::with_context(|ctx| Some(ctx.pixels_per_point()))
+            .map(|pixels_per_point| pixels_per_point * 2.0);
+        let Some(Action::Ui(UiAction::Context(run))) = command.into_actions().pop() else {
+            panic!("expected a context action");
+        };
+
+        let output = run(&egui::Context::default()).expect("a message");
+        assert_eq!(resolve(output), [2.0]);
+    }
+
+    #[test]
+    fn load_texture_uploads_once_and_reuses_the_cache() {
+        let ctx = egui::Context::default();
+        let load = || {
+            let image = egui::ColorImage::filled([4, 2], egui::Color32::WHITE);
+            Command::load_texture("white", image, Default::default(), |result| {
+                result.map(|handle| handle.id())
+            })
+        };
+
+        let Some(Action::Ui(UiAction::Effect(run))) = load().into_actions().pop() else {
+            panic!("expected an effect action");
+        };
+        let Some(Action::Spawn(task)) = run(&ctx).into_actions().pop() else {
+            panic!("an uncached texture should be decoded in a task");
+        };
+        let Some(CommandOutput::Command(upload)) = block_on(task.stream.into_future()).0 else {
+            panic!("decoding should hand the upload back to the UI thread");
+        };
+        let Some(Action::Ui(UiAction::Context(upload))) = upload.into_actions().pop() else {
+            panic!("expected a context action");
+        };
+        let uploaded = resolve(upload(&ctx).expect("upload should produce a message"))
+            .pop()
+            .unwrap()
+            .unwrap();
+
+        let Some(Action::Ui(UiAction::Effect(run))) = load().into_actions().pop() else {
+            panic!("expected an effect action");
+        };
+        let cached = messages(run(&ctx)).pop().unwrap().unwrap();
+        assert_eq!(cached, uploaded);
+    }
+
+    #[test]
+    fn load_texture_tags_its_decoding_task() {
+        let image = egui::ColorImage::filled([1, 1], egui::Color32::WHITE);
+        let command = Command::load_texture("thumb", image, Default::default(), |_| ())
+            .latest_wins("avatar")
+            .in_group("thumbnails")
+            .with_label("avatar");
+        let Some(Action::Ui(UiAction::Effect(run))) = command.into_actions().pop() else {
+            panic!("expected an effect action");
+        };
+        let Some(Action::Spawn(task)) = run(&egui::Context::default()).into_actions().pop() else {
+            panic!("an uncached texture should be decoded in a task");
+        };
+
+        assert!(task.key == Some(CommandKey::new("avatar")));
+        assert!(task.latest);
+        assert_eq!(task.group.as_deref(), Some("thumbnails"));
+        assert_eq!(task.label.as_deref(), Some("avatar"));
+    }
+
+    #[test]
+    fn storage_get_maps_the_stored_value() {
+        let command = Command::storage_get("volume", |value| value.map(|value| value.len()))
+            .map(|length| length.unwrap_or(0) * 10);
+        let Some(Action::Ui(UiAction::StorageGet(key, read))) = command.into_actions().pop() else {
+            panic!("expected a storage read");
+        };
+
+        assert_eq!(key, "volume");
+        assert_eq!(resolve(read(Some("0.75".into()))), [40]);
+    }
+
+    #[test]
+    fn set_theme_applies_the_preference() {
+        let command: Command<()> = Command::set_theme(egui::ThemePreference::Light);
+        let Some(Action::Ui(UiAction::Context(run))) = command.into_actions().pop() else {
+            panic!("expected a context action");
+        };
+        let ctx = egui::Context::default();
+
+        assert!(run(&ctx).is_none());
+        assert_eq!(
+            ctx.options(|options| options.theme_preference),
+            egui::ThemePreference::Light
+        );
+    }
+
+    #[test]
+    fn focus_commands_target_the_given_id() {
+        let id = egui::Id::new("email");
+        let command: Command<()> = Command::sequence([Command::focus(id), Command::scroll_to(id)]);
+        let Some(Action::Spawn(task)) = command.into_actions().pop() else {
+            panic!("sequence should spawn a single task");
+        };
+        let actions: Vec<_> = block_on(task.stream.collect::<Vec<_>>())
+            .into_iter()
+            .flat_map(|output| match output {
+                CommandOutput::Command(command) => command.actions,
+                _ => Vec::new(),
+            })
+            .collect();
+
+        assert!(matches!(
+            &actions[..],
+            [
+                Action::Ui(UiAction::Focus(first)),
+                Action::Ui(UiAction::ScrollTo(second, None)),
+            ] if *first == id && *second == id
+        ));
+    }
+
+    #[test]
+    fn and_then_chains_follow_up_commands() {
+        let command = Command::message(2)
+            .and_then(|value| Command::batch([Command::message(value * 10)]))
+            .and_then(|value| Command::message(value + 1));
+
+        assert_eq!(messages(command), vec![21]);
+    }
+
+    #[test]
+    fn and_then_chains_off_ui_effects() {
+        let command =
+            Command::with_context(|_| Some(1)).and_then(|value| Command::message(value + 100));
+        let Some(Action::Ui(UiAction::Context(run))) = command.into_actions().pop() else {
+            panic!("expected a context action");
+        };
+        let output = run(&egui::Context::default()).expect("a follow-up command");
+        assert_eq!(resolve(output), [101]);
+
+        let command = Command::read_clipboard(|text| text.len())
+            .map(|len| len * 2)
+            .and_then(|len| Command::message(len + 1));
+        let Some(Action::Ui(UiAction::ReadClipboard(read))) = command.into_actions().pop() else {
+            panic!("expected a clipboard read");
+        };
+        assert_eq!(resolve(read("abc".to_owned())), [7]);
+    }
+
+    #[test]
+    fn aborting_drops_messages_of_ui_effects() {
+        let (command, handle) = Command::read_clipboard(|text| text.len()).abortable();
+        let Some(Action::Ui(UiAction::ReadClipboard(read))) = command.into_actions().pop() else {
+            panic!("expected a clipboard read");
+        };
+        handle.abort();
+        assert!(resolve(read("abc".to_owned())).is_empty());
+
+        // Tasks an effect starts later are tied to the same handle.
+        let (command, handle) =
+            Command::from_ui(UiAction::Effect(Box::new(|_| Command::message(1)))).abortable();
+        let Some(Action::Ui(UiAction::Effect(run))) = command.into_actions().pop() else {
+            panic!("expected an effect");
+        };
+        let follow_up = run(&egui::Context::default());
+        handle.abort();
+        assert!(messages(follow_up).is_empty());
+    }
 }
@@ -3,17 +3,22 @@
 #[cfg(feature = "runtime")]
 pub mod app;
 pub mod command;
+mod pool;
 pub mod program;
+pub mod retry;
 pub mod subscription;
+pub mod texture;
 pub mod view;
 
 pub mod prelude {
     #[cfg(feature = "runtime")]
-    pub use crate::app::{run, run_with_native_options};
+    pub use crate::app::{run, run_with_native_options, PendingTask, TaskFailure, TaskOrigin};
     pub use crate::{
-        command::Command,
+        command::{Command, CommandError, CommandHandle, CommandKey},
         program::Program,
+        retry::RetryPolicy,
         subscription::{IntoSubscription, StreamSubscription, Subscription, SubscriptionToken},
+        texture::{TextureError, TextureSource},
         view::ViewContext,
     };
 }
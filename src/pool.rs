@@ -0,0 +1,107 @@
+use std::{
+    any::Any,
+    future::Future,
+    num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex, OnceLock},
+    thread,
+};
+
+use futures::channel::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+type Outcome<T> = Result<T, Box<dyn Any + Send>>;
+
+/// Runs a blocking operation off the async workers.
+///
+/// Uses tokio's blocking pool when called from within a tokio runtime and falls back to a
+/// dedicated thread otherwise. Panics are propagated to the awaiting task.
+pub(crate) fn blocking<F, T>(op: F) -> impl Future<Output = T> + Send
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    let job = move || {
+        let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(op)));
+    };
+
+    #[cfg(feature = "runtime")]
+    if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        drop(handle.spawn_blocking(job));
+        return resume(receiver);
+    }
+
+    thread::Builder::new()
+        .name("egui_elm-blocking".into())
+        .spawn(job)
+        .expect("failed to spawn blocking thread");
+
+    resume(receiver)
+}
+
+/// Runs a CPU-bound operation on the dedicated compute pool.
+///
+/// The pool is started lazily with one thread per available core. Panics are propagated to
+/// the awaiting task.
+pub(crate) fn compute<F, T>(op: F) -> impl Future<Output = T> + Send
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    ComputePool::global().execute(Box::new(move || {
+        let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(op)));
+    }));
+
+    resume(receiver)
+}
+
+async fn resume<T>(receiver: oneshot::Receiver<Outcome<T>>) -> T {
+    match receiver.await {
+        Ok(Ok(value)) => value,
+        Ok(Err(payload)) => panic::resume_unwind(payload),
+        Err(oneshot::Canceled) => panic!("offloaded operation was dropped before completing"),
+    }
+}
+
+struct ComputePool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl ComputePool {
+    fn global() -> &'static Self {
+        static POOL: OnceLock<ComputePool> = OnceLock::new();
+        POOL.get_or_init(Self::start)
+    }
+
+    fn start() -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+        for index in 0..workers {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("egui_elm-compute-{index}"))
+                .spawn(move || loop {
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+                .expect("failed to spawn compute worker");
+        }
+
+        Self { jobs }
+    }
+
+    fn execute(&self, job: Job) {
+        let _ = self.jobs.send(job);
+    }
+}
@@ -1,3 +1,6 @@
+#[cfg(feature = "runtime")]
+use std::borrow::Cow;
+
 use crate::{
     command::Command,
     subscription::{IntoSubscription, Subscription},
@@ -10,6 +13,15 @@ type SaveHandler<Model> = fn(&mut Model, &mut dyn eframe::Storage);
 #[cfg(feature = "runtime")]
 type ExitHandler<Model> = fn(&mut Model, Option<&glow::Context>);
 
+#[cfg(feature = "runtime")]
+type ErrorHandler<Message> = fn(crate::command::CommandError) -> Message;
+
+#[cfg(feature = "runtime")]
+type PasteHandler<Message> = fn(&str) -> Option<Message>;
+
+#[cfg(feature = "runtime")]
+type TaskFailureHandler<Message> = fn(crate::app::TaskFailure) -> Message;
+
 /// Describes the four pure functions that make up an Elm-style program.
 pub struct Program<Model, Message, Sub = Subscription<Message>>
 where
@@ -25,6 +37,14 @@ where
     pub(crate) save: Option<SaveHandler<Model>>,
     #[cfg(feature = "runtime")]
     pub(crate) on_exit: Option<ExitHandler<Model>>,
+    #[cfg(feature = "runtime")]
+    pub(crate) concurrency_limits: Vec<(Cow<'static, str>, usize)>,
+    #[cfg(feature = "runtime")]
+    pub(crate) on_error: Option<ErrorHandler<Message>>,
+    #[cfg(feature = "runtime")]
+    pub(crate) on_task_failure: Option<TaskFailureHandler<Message>>,
+    #[cfg(feature = "runtime")]
+    pub(crate) on_paste: Option<PasteHandler<Message>>,
 }
 
 impl<Model, Message, Sub> Program<Model, Message, Sub>
@@ -49,6 +69,14 @@ where
             save: None,
             #[cfg(feature = "runtime")]
             on_exit: None,
+            #[cfg(feature = "runtime")]
+            concurrency_limits: Vec::new(),
+            #[cfg(feature = "runtime")]
+            on_error: None,
+            #[cfg(feature = "runtime")]
+            on_task_failure: None,
+            #[cfg(feature = "runtime")]
+            on_paste: None,
         }
     }
 }
@@ -71,4 +99,47 @@ where
         self.on_exit = Some(on_exit);
         self
     }
+
+    /// Registers a callback that turns paste events (e.g. Ctrl+V) into messages.
+    ///
+    /// The paste still reaches focused text fields; return `None` to ignore it here.
+    pub fn with_paste_handler(mut self, on_paste: PasteHandler<Message>) -> Self {
+        self.on_paste = Some(on_paste);
+        self
+    }
+
+    /// Registers a mapper that turns errors of [`Command::fallible`] commands into a message.
+    ///
+    /// Without a handler such errors are logged as warnings and otherwise dropped.
+    pub fn with_error_handler(mut self, on_error: ErrorHandler<Message>) -> Self {
+        self.on_error = Some(on_error);
+        self
+    }
+
+    /// Registers a mapper that turns panics in command tasks and subscription streams into a
+    /// message, so the UI can recover instead of waiting for a reply that never arrives.
+    ///
+    /// Without a handler such panics are logged as warnings and otherwise dropped.
+    pub fn with_task_failure_handler(
+        mut self,
+        on_task_failure: TaskFailureHandler<Message>,
+    ) -> Self {
+        self.on_task_failure = Some(on_task_failure);
+        self
+    }
+
+    /// Limits how many tasks of a [command group](crate::command::Command::in_group) run at once.
+    ///
+    /// Further tasks of the group are queued and start in order as running ones finish.
+    pub fn with_concurrency_limit(
+        mut self,
+        group: impl Into<Cow<'static, str>>,
+        limit: usize,
+    ) -> Self {
+        let group = group.into();
+        self.concurrency_limits
+            .retain(|(existing, _)| *existing != group);
+        self.concurrency_limits.push((group, limit.max(1)));
+        self
+    }
 }
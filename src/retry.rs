@@ -0,0 +1,230 @@
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use futures_timer::Delay;
+
+/// Upper bound for every delay, so huge backoffs still make a valid timer.
+const DELAY_CEILING: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Describes how often and how quickly a failing operation is retried.
+///
+/// Used by [`Command::retry`](crate::command::Command::retry).
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    backoff: Backoff,
+    max_attempts: u32,
+    max_delay: Option<Duration>,
+    jitter: f64,
+}
+
+/// Strategy used to compute the delay between two attempts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backoff {
+    /// Waits the same amount of time after every failure.
+    Fixed(Duration),
+    /// Waits `step` after the first failure, `2 * step` after the second, and so on.
+    Linear(Duration),
+    /// Waits `base` after the first failure and multiplies the delay by `factor` afterwards.
+    ///
+    /// `factor` must be finite and at least one.
+    Exponential { base: Duration, factor: f64 },
+}
+
+impl RetryPolicy {
+    /// Creates a policy with the given backoff, three attempts and no jitter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an exponential backoff has a factor below one, or one that is not finite.
+    pub fn new(backoff: Backoff) -> Self {
+        if let Backoff::Exponential { factor, .. } = backoff {
+            assert!(
+                factor.is_finite() && factor >= 1.0,
+                "exponential backoff factor must be finite and at least 1, got {factor}"
+            );
+        }
+        Self {
+            backoff,
+            max_attempts: 3,
+            max_delay: None,
+            jitter: 0.0,
+        }
+    }
+
+    /// Retries after a constant delay.
+    pub fn fixed(delay: Duration) -> Self {
+        Self::new(Backoff::Fixed(delay))
+    }
+
+    /// Retries after a delay that grows by `step` with every failure.
+    pub fn linear(step: Duration) -> Self {
+        Self::new(Backoff::Linear(step))
+    }
+
+    /// Retries after a delay that doubles with every failure, starting at `base`.
+    pub fn exponential(base: Duration) -> Self {
+        Self::new(Backoff::Exponential { base, factor: 2.0 })
+    }
+
+    /// Sets the total number of attempts, including the first one. Values below one are
+    /// treated as one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Caps the delay between two attempts.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+
+    /// Randomizes every delay by up to `fraction` in either direction, e.g. `0.2` for ±20%.
+    ///
+    /// Spreads out retries of many clients that failed at the same time.
+    pub fn jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Returns the total number of attempts allowed by this policy.
+    pub fn attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Returns the delay to wait after the given failed attempt, starting at one, before
+    /// jitter is applied. Delays never exceed a day, even without [`RetryPolicy::max_delay`].
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let attempt = attempt.max(1);
+        let delay = match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Linear(step) => step.saturating_mul(attempt),
+            Backoff::Exponential { base, factor } => {
+                let exponent = i32::try_from(attempt - 1).unwrap_or(i32::MAX);
+                Duration::try_from_secs_f64(base.as_secs_f64() * factor.powi(exponent))
+                    .unwrap_or(DELAY_CEILING)
+            }
+        };
+
+        let delay = delay.min(DELAY_CEILING);
+        match self.max_delay {
+            Some(max_delay) => delay.min(max_delay),
+            None => delay,
+        }
+    }
+
+    fn jittered_delay(&self, attempt: u32) -> Duration {
+        let delay = self.delay(attempt);
+        if self.jitter == 0.0 {
+            return delay;
+        }
+
+        let scale = 1.0 + self.jitter * (2.0 * random_unit() - 1.0);
+        Duration::try_from_secs_f64(delay.as_secs_f64() * scale).unwrap_or(delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts with an exponential backoff starting at 100ms.
+    fn default() -> Self {
+        Self::exponential(Duration::from_millis(100))
+    }
+}
+
+/// Runs `op` until it succeeds or the policy runs out of attempts.
+pub(crate) async fn retry<Op, Fut, T, E>(policy: RetryPolicy, mut op: Op) -> Result<T, E>
+where
+    Op: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(_) if attempt < policy.max_attempts => {
+                Delay::new(policy.jittered_delay(attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns a pseudo-random number in `[0, 1)`, good enough to spread out retries.
+fn random_unit() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn backoff_strategies_compute_delays() {
+        let fixed = RetryPolicy::fixed(Duration::from_millis(50));
+        assert_eq!(fixed.delay(3), Duration::from_millis(50));
+
+        let linear = RetryPolicy::linear(Duration::from_millis(50));
+        assert_eq!(linear.delay(3), Duration::from_millis(150));
+
+        let exponential = RetryPolicy::exponential(Duration::from_millis(50));
+        assert_eq!(exponential.delay(1), Duration::from_millis(50));
+        assert_eq!(exponential.delay(4), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn max_delay_caps_backoff() {
+        let policy =
+            RetryPolicy::exponential(Duration::from_secs(1)).max_delay(Duration::from_secs(5));
+
+        assert_eq!(policy.delay(10), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn huge_delays_stop_at_the_ceiling() {
+        let exponential = RetryPolicy::exponential(Duration::from_secs(1));
+        assert_eq!(exponential.delay(u32::MAX), DELAY_CEILING);
+
+        let linear = RetryPolicy::linear(Duration::MAX);
+        assert_eq!(linear.delay(2), DELAY_CEILING);
+        assert!(linear.jitter(1.0).jittered_delay(2) <= 2 * DELAY_CEILING);
+    }
+
+    #[test]
+    #[should_panic(expected = "exponential backoff factor")]
+    fn invalid_backoff_factors_are_rejected() {
+        RetryPolicy::new(Backoff::Exponential {
+            base: Duration::from_secs(1),
+            factor: f64::NAN,
+        });
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let policy = RetryPolicy::fixed(Duration::from_millis(100)).jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.jittered_delay(1);
+            assert!(delay >= Duration::from_millis(50));
+            assert!(delay <= Duration::from_millis(150));
+        }
+    }
+
+    #[test]
+    fn retry_stops_after_max_attempts() {
+        let mut calls = 0;
+        let policy = RetryPolicy::fixed(Duration::ZERO).max_attempts(4);
+        let result: Result<(), u32> = block_on(retry(policy, || {
+            calls += 1;
+            let attempt = calls;
+            async move { Err(attempt) }
+        }));
+
+        assert_eq!(result, Err(4));
+        assert_eq!(calls, 4);
+    }
+}
@@ -1,13 +1,19 @@
 use std::{
-    any::Any,
+    any::{Any, TypeId},
+    future::{self, Future},
+    panic::Location,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex, PoisonError},
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use async_stream::stream;
-use futures::{stream::SelectAll, Stream, StreamExt};
+use futures::{
+    channel::mpsc,
+    stream::{self, SelectAll},
+    Stream, StreamExt,
+};
 use futures_timer::Delay;
 
 /// Trait implemented by values that can be converted into a subscription stream.
@@ -19,19 +25,83 @@ where
     type Stream: Stream<Item = Message> + Send + 'static;
 
     /// Returns an optional identity token for this subscription.
+    ///
+    /// The runtime keeps the stream running while the token stays equal. Without a token the
+    /// stream is restarted after every message.
     fn identity(&self) -> Option<SubscriptionToken>;
 
     /// Consumes the subscription and returns the underlying stream.
     fn into_stream(self) -> Self::Stream;
+
+    /// Converts the value into a boxed [`Subscription`], which is what the runtime runs.
+    fn into_subscription(self) -> Subscription<Message>
+    where
+        Self: Sized,
+    {
+        let token = self.identity();
+        Subscription::from_stream(self.into_stream()).with_token_option(token)
+    }
+}
+
+/// Event the runtime observes while rendering a frame and passes to subscription listeners.
+#[cfg_attr(not(feature = "runtime"), allow(dead_code))]
+pub(crate) enum FrameEvent<'a> {
+    /// The theme of the operating system changed, or was observed for the first time.
+    SystemThemeChanged(egui::Theme),
+    /// An input event of the frame that is about to be rendered.
+    Input(&'a egui::Event),
+    /// A frame is about to be rendered.
+    Frame { dt: Duration, now: Instant },
+}
+
+/// Boxed stream driven by a [`Subscription`].
+type BoxedStream<Message> = Pin<Box<dyn Stream<Item = Message> + Send>>;
+
+/// Callback fed by the runtime with [`FrameEvent`]s instead of running as a stream.
+pub(crate) type ListenerFn<Message> = Box<dyn FnMut(&FrameEvent<'_>) -> Option<Message> + Send>;
+
+/// Listener together with the call site it was created at, so the runtime can tell new
+/// listeners from ones it has already fed.
+pub(crate) struct Listener<Message> {
+    pub(crate) token: SubscriptionToken,
+    pub(crate) callback: ListenerFn<Message>,
+}
+
+/// Single stream of a subscription, which the runtime keeps running while its token stays.
+pub(crate) struct Child<Message> {
+    pub(crate) token: Option<SubscriptionToken>,
+    pub(crate) stream: BoxedStream<Message>,
+}
+
+/// Merges the streams of the children fairly, pending forever if there are none.
+fn merge<Message>(children: Vec<Child<Message>>) -> BoxedStream<Message>
+where
+    Message: Send + 'static,
+{
+    if children.is_empty() {
+        return Box::pin(futures::stream::pending());
+    }
+    Box::pin(
+        children
+            .into_iter()
+            .map(|child| child.stream)
+            .collect::<SelectAll<_>>(),
+    )
 }
 
 /// Represents a continuous stream of incoming messages for an Elm program.
+///
+/// A batch keeps its children apart, so the runtime can compare them one by one: children
+/// whose token is unchanged keep running, new ones are started and removed ones are aborted.
 pub struct Subscription<Message>
 where
     Message: Send + 'static,
 {
-    stream: Pin<Box<dyn Stream<Item = Message> + Send>>,
-    token: Option<SubscriptionToken>,
+    children: Vec<Child<Message>>,
+    listeners: Vec<Listener<Message>>,
+    /// Whether a listener wants a frame to be rendered continuously, see
+    /// [`Subscription::on_frame`].
+    animates: bool,
 }
 
 impl<Message> Subscription<Message>
@@ -41,46 +111,58 @@ where
     /// Creates a subscription that yields no values.
     pub fn none() -> Self {
         Self {
-            stream: Box::pin(futures::stream::pending()),
-            token: Some(SubscriptionToken::new(())),
+            children: Vec::new(),
+            listeners: Vec::new(),
+            animates: false,
         }
     }
 
     /// Creates a subscription from any stream of messages.
+    ///
+    /// The subscription is identified by the place it is created at and the type of the
+    /// stream, so it keeps running across calls to `subscription`. Subscriptions created in a
+    /// loop share that identity and need distinct tokens, see [`Subscription::with_token`].
+    #[track_caller]
     pub fn from_stream<S>(stream: S) -> Self
     where
         S: Stream<Item = Message> + Send + 'static,
     {
+        let token = SubscriptionToken::new((Location::caller(), TypeId::of::<S>()));
         Self {
-            stream: Box::pin(stream),
-            token: None,
+            children: vec![Child {
+                token: Some(token),
+                stream: Box::pin(stream),
+            }],
+            listeners: Vec::new(),
+            animates: false,
         }
     }
 
     /// Batches multiple subscriptions into a single stream of messages.
+    ///
+    /// Every child keeps its own token, so changing one child does not restart the others.
     pub fn batch(subscriptions: impl IntoIterator<Item = Self>) -> Self {
-        let mut select_all: SelectAll<_> = SelectAll::new();
-        let mut tokens = Vec::new();
-        let mut missing_identity = false;
+        let mut children = Vec::new();
+        let mut listeners = Vec::new();
+        let mut animates = false;
         for subscription in subscriptions {
-            match subscription.token {
-                Some(token) => tokens.push(token),
-                None => missing_identity = true,
-            }
-            select_all.push(subscription.stream);
+            children.extend(subscription.children);
+            listeners.extend(subscription.listeners);
+            animates |= subscription.animates;
         }
 
         Self {
-            stream: Box::pin(select_all),
-            token: if missing_identity {
-                None
-            } else {
-                Some(SubscriptionToken::new(tokens))
-            },
+            children,
+            listeners,
+            animates,
         }
     }
 
     /// Creates a subscription by periodically emitting a cloned message.
+    ///
+    /// Identified by its call site and `duration`; the message is captured when the timer
+    /// starts, so attach a token that includes it if it can change.
+    #[track_caller]
     pub fn interval(duration: Duration, message: Message) -> Self
     where
         Message: Clone,
@@ -89,10 +171,15 @@ where
     }
 
     /// Creates a subscription by periodically invoking the provided closure.
+    ///
+    /// Identified by its call site and `duration`, so changing the duration restarts the
+    /// timer while re-creating it with the same one does not.
+    #[track_caller]
     pub fn interval_with<F>(duration: Duration, mut message_factory: F) -> Self
     where
         F: FnMut() -> Message + Send + 'static,
     {
+        let token = SubscriptionToken::new((Location::caller(), TypeId::of::<F>(), duration));
         let stream = stream! {
             loop {
                 Delay::new(duration).await;
@@ -100,36 +187,259 @@ where
             }
         };
 
-        Self::from_stream(stream)
+        Self::from_stream(stream).with_token_option(Some(token))
+    }
+
+    /// Creates a subscription driven by a long-lived worker that reports through a channel.
+    ///
+    /// The worker is started once and receives the sending half of a channel with room for
+    /// `capacity` messages. It keeps running for as long as `subscription` returns a channel
+    /// with the same `id`, and is aborted when it no longer does. To let `update` talk back,
+    /// the worker creates its own channel and sends its sender out in a message, which the
+    /// model stores.
+    pub fn channel<I, F, Fut>(id: I, capacity: usize, worker: F) -> Self
+    where
+        I: PartialEq + Send + Sync + 'static,
+        F: FnOnce(mpsc::Sender<Message>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        // Built on first poll, so channels that are only compared against running ones never
+        // start their worker.
+        let stream = stream::once(async move {
+            let (output, messages) = mpsc::channel(capacity);
+            let worker = stream::once(worker(output)).filter_map(|()| future::ready(None));
+            stream::select(messages, worker)
+        })
+        .flatten();
+
+        Self::from_stream(stream).with_token((TypeId::of::<F>(), id))
+    }
+
+    /// Creates a subscription that fires when the theme of the operating system changes.
+    ///
+    /// The runtime reads the theme from each frame's input, so the subscription also fires
+    /// with the current theme in the first frame after it becomes active.
+    #[track_caller]
+    pub fn system_theme<F>(f: F) -> Self
+    where
+        F: Fn(egui::Theme) -> Message + Send + 'static,
+    {
+        Self::from_listener(move |event| match event {
+            FrameEvent::SystemThemeChanged(theme) => Some(f(*theme)),
+            _ => None,
+        })
+    }
+
+    /// Creates a subscription that turns raw egui input events into messages.
+    ///
+    /// The runtime feeds it every event of a frame before `view` runs, so global input can be
+    /// handled in `update` instead of being polled through `ctx.input` in view code.
+    #[track_caller]
+    pub fn on_event<F>(f: F) -> Self
+    where
+        F: Fn(&egui::Event) -> Option<Message> + Send + 'static,
+    {
+        Self::from_listener(move |event| match event {
+            FrameEvent::Input(event) => f(event),
+            _ => None,
+        })
+    }
+
+    /// Creates a subscription that fires when a key is pressed, including key repeats.
+    ///
+    /// The closure receives the key together with the held modifiers, ready to be compared
+    /// with shortcuts such as `KeyboardShortcut::new(Modifiers::COMMAND, Key::S)`.
+    #[track_caller]
+    pub fn key_pressed<F>(f: F) -> Self
+    where
+        F: Fn(egui::KeyboardShortcut) -> Option<Message> + Send + 'static,
+    {
+        Self::on_event(move |event| match event {
+            egui::Event::Key {
+                key,
+                pressed: true,
+                modifiers,
+                ..
+            } => f(egui::KeyboardShortcut::new(*modifiers, *key)),
+            _ => None,
+        })
+    }
+
+    /// Creates a subscription that fires when a pointer button is pressed (`true`) or
+    /// released (`false`) at the given position.
+    #[track_caller]
+    pub fn pointer_button<F>(f: F) -> Self
+    where
+        F: Fn(egui::PointerButton, egui::Pos2, bool) -> Option<Message> + Send + 'static,
+    {
+        Self::on_event(move |event| match event {
+            egui::Event::PointerButton {
+                button,
+                pos,
+                pressed,
+                ..
+            } => f(*button, *pos, *pressed),
+            _ => None,
+        })
+    }
+
+    /// Creates a subscription that fires when the pointer moves.
+    #[track_caller]
+    pub fn pointer_moved<F>(f: F) -> Self
+    where
+        F: Fn(egui::Pos2) -> Option<Message> + Send + 'static,
+    {
+        Self::on_event(move |event| match event {
+            egui::Event::PointerMoved(pos) => f(*pos),
+            _ => None,
+        })
+    }
+
+    /// Creates a subscription that fires for mouse wheel and touchpad scrolling.
+    ///
+    /// The delta is reported in the given unit, as delivered by the platform.
+    #[track_caller]
+    pub fn scrolled<F>(f: F) -> Self
+    where
+        F: Fn(egui::Vec2, egui::MouseWheelUnit) -> Option<Message> + Send + 'static,
+    {
+        Self::on_event(move |event| match event {
+            egui::Event::MouseWheel { delta, unit, .. } => f(*delta, *unit),
+            _ => None,
+        })
+    }
+
+    /// Creates a subscription that fires once per rendered frame.
+    ///
+    /// The closure receives egui's `stable_dt`, the smoothed time between frames, and the
+    /// time the frame started. While the subscription is active the runtime renders frames
+    /// continuously; otherwise it only repaints when there is something to handle.
+    #[track_caller]
+    pub fn on_frame<F>(f: F) -> Self
+    where
+        F: Fn(Duration, Instant) -> Message + Send + 'static,
+    {
+        let mut subscription = Self::from_listener(move |event| match event {
+            FrameEvent::Frame { dt, now } => Some(f(*dt, *now)),
+            _ => None,
+        });
+        subscription.animates = true;
+        subscription
     }
 
     /// Maps the output of the subscription into a different message type.
+    ///
+    /// All streams and listeners of the subscription share `f`, so keep it cheap.
     pub fn map<F, Output>(self, f: F) -> Subscription<Output>
     where
         F: FnMut(Message) -> Output + Send + 'static,
         Output: Send + 'static,
     {
-        let token = self.token.clone();
-        let mapped_stream = self.stream.map(f);
-        Subscription::from_stream(mapped_stream).with_token_option(token)
+        // Shared by the children, which run as separate tasks, and the listeners, which run on
+        // the UI thread. The lock serializes the calls, so a slow `f` in a stream can briefly
+        // hold up a listener. If `f` panics the lock is recovered rather than left poisoned.
+        let f = Arc::new(Mutex::new(f));
+        let listeners = self
+            .listeners
+            .into_iter()
+            .map(|listener| {
+                let f = f.clone();
+                let mut callback = listener.callback;
+                Listener {
+                    token: listener.token,
+                    callback: Box::new(move |event: &FrameEvent<'_>| {
+                        callback(event).map(|message| {
+                            (f.lock().unwrap_or_else(PoisonError::into_inner))(message)
+                        })
+                    }),
+                }
+            })
+            .collect();
+        let children = self
+            .children
+            .into_iter()
+            .map(|child| {
+                let f = f.clone();
+                Child {
+                    token: child.token,
+                    stream: Box::pin(child.stream.map(move |message| {
+                        (f.lock().unwrap_or_else(PoisonError::into_inner))(message)
+                    })),
+                }
+            })
+            .collect();
+
+        Subscription {
+            children,
+            listeners,
+            animates: self.animates,
+        }
     }
 
     /// Attaches a token so the runtime can detect identical subscriptions.
-    pub fn with_token<T>(mut self, token: T) -> Self
+    ///
+    /// The streams of a batch are merged and identified by the token as a whole.
+    pub fn with_token<T>(self, token: T) -> Self
     where
         T: PartialEq + Send + Sync + 'static,
     {
-        self.token = Some(SubscriptionToken::new(token));
-        self
+        self.with_token_option(Some(SubscriptionToken::new(token)))
     }
 
     fn with_token_option(mut self, token: Option<SubscriptionToken>) -> Self {
-        self.token = token;
+        let stream = match self.children.len() {
+            0 => return self,
+            1 => self.children.pop().expect("one child").stream,
+            _ => Box::pin(
+                self.children
+                    .drain(..)
+                    .map(|child| child.stream)
+                    .collect::<SelectAll<_>>(),
+            ),
+        };
+        self.children = vec![Child { token, stream }];
         self
     }
 
+    #[track_caller]
+    fn from_listener<L>(listener: L) -> Self
+    where
+        L: FnMut(&FrameEvent<'_>) -> Option<Message> + Send + 'static,
+    {
+        Self {
+            children: Vec::new(),
+            listeners: vec![Listener {
+                token: SubscriptionToken::new((Location::caller(), TypeId::of::<L>())),
+                callback: Box::new(listener),
+            }],
+            animates: false,
+        }
+    }
+
+    /// Returns `true` if the runtime has to render frames continuously for this
+    /// subscription.
+    #[cfg_attr(not(feature = "runtime"), allow(dead_code))]
+    pub(crate) fn animates(&self) -> bool {
+        self.animates
+    }
+
+    #[cfg_attr(not(feature = "runtime"), allow(dead_code))]
+    pub(crate) fn into_parts(self) -> (Vec<Child<Message>>, Vec<Listener<Message>>) {
+        (self.children, self.listeners)
+    }
+
+    /// Combines the tokens of all children, or returns `None` if any child has none.
     fn identity(&self) -> Option<SubscriptionToken> {
-        self.token.clone()
+        let tokens = self
+            .children
+            .iter()
+            .map(|child| child.token.clone())
+            .collect::<Option<Vec<_>>>()?;
+        Some(SubscriptionToken::new(tokens))
+    }
+
+    fn into_merged_stream(self) -> BoxedStream<Message> {
+        merge(self.children)
     }
 }
 
@@ -149,7 +459,13 @@ where
     type Item = Message;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.stream.as_mut().poll_next(cx)
+        // Polled directly, the children are merged into one stream on first use.
+        if self.children.len() != 1 {
+            let token = self.identity();
+            let stream = merge(std::mem::take(&mut self.children));
+            self.children.push(Child { token, stream });
+        }
+        self.children[0].stream.as_mut().poll_next(cx)
     }
 }
 
@@ -157,14 +473,18 @@ impl<Message> IntoSubscription<Message> for Subscription<Message>
 where
     Message: Send + 'static,
 {
-    type Stream = Pin<Box<dyn Stream<Item = Message> + Send>>;
+    type Stream = BoxedStream<Message>;
 
     fn identity(&self) -> Option<SubscriptionToken> {
         self.identity()
     }
 
     fn into_stream(self) -> Self::Stream {
-        self.stream
+        self.into_merged_stream()
+    }
+
+    fn into_subscription(self) -> Subscription<Message> {
+        self
     }
 }
 
@@ -184,10 +504,17 @@ where
     S: Stream<Item = Message> + Send + 'static,
 {
     /// Wraps the provided stream.
+    ///
+    /// Like [`Subscription::from_stream`], the subscription is identified by its call site
+    /// and stream type until a token is attached.
+    #[track_caller]
     pub fn new(stream: S) -> Self {
         Self {
             stream,
-            token: None,
+            token: Some(SubscriptionToken::new((
+                Location::caller(),
+                TypeId::of::<S>(),
+            ))),
         }
     }
 
@@ -203,8 +530,12 @@ where
     /// Converts the typed subscription into the boxed variant.
     pub fn boxed(self) -> Subscription<Message> {
         Subscription {
-            stream: Box::pin(self.stream),
-            token: self.token,
+            children: vec![Child {
+                token: self.token,
+                stream: Box::pin(self.stream),
+            }],
+            listeners: Vec::new(),
+            animates: false,
         }
     }
 }
@@ -305,6 +636,73 @@ mod tests {
         assert!(result.contains(&3));
     }
 
+    #[test]
+    fn polled_batches_are_fair_and_stay_pending_when_empty() {
+        let busy = Subscription::from_stream(stream::repeat(1_u32));
+        let quiet = Subscription::from_stream(stream::iter([2_u32]));
+        let values = block_on(
+            Subscription::batch([busy, quiet])
+                .take(4)
+                .collect::<Vec<_>>(),
+        );
+        assert!(values.contains(&2));
+
+        let mut empty = Subscription::<u32>::batch(Vec::new());
+        assert!(futures::FutureExt::now_or_never(empty.next()).is_none());
+    }
+
+    #[test]
+    fn batch_keeps_the_token_of_every_child() {
+        let socket =
+            |id: u32| Subscription::from_stream(futures::stream::pending::<u32>()).with_token(id);
+        let subscription = Subscription::batch(vec![
+            socket(1),
+            Subscription::batch(vec![socket(2), socket(3)]),
+        ])
+        .map(|value| value + 1);
+
+        let (children, _) = subscription.into_parts();
+        let tokens: Vec<_> = children.into_iter().map(|child| child.token).collect();
+        let expected = [1_u32, 2, 3].map(|id| Some(SubscriptionToken::new(id)));
+        assert!(tokens == expected);
+
+        let merged = Subscription::batch(vec![socket(1), socket(2)]).with_token("sockets");
+        let (children, _) = merged.into_parts();
+        assert_eq!(children.len(), 1);
+        assert!(children[0].token == Some(SubscriptionToken::new("sockets")));
+    }
+
+    #[test]
+    fn call_site_identifies_untokened_subscriptions() {
+        let token = |subscription: Subscription<u32>| subscription.identity().unwrap();
+        let ticks = |duration| Subscription::interval(duration, 1);
+        let events = || Subscription::from_stream(futures::stream::pending());
+
+        assert!(token(ticks(Duration::from_secs(1))) == token(ticks(Duration::from_secs(1))));
+        assert!(token(ticks(Duration::from_secs(1))) != token(ticks(Duration::from_secs(2))));
+        assert!(token(events()) == token(events()));
+        assert!(token(events()) != token(Subscription::from_stream(futures::stream::pending())));
+        assert!(token(events().with_token(7)) == token(events().with_token(7)));
+    }
+
+    #[test]
+    fn channel_forwards_worker_messages() {
+        use futures::SinkExt;
+
+        let connect = |id: u32| {
+            Subscription::channel(id, 4, move |mut output| async move {
+                for message in [id, id * 10] {
+                    let _ = output.send(message).await;
+                }
+            })
+        };
+        assert!(connect(1).identity() == connect(1).identity());
+        assert!(connect(1).identity() != connect(2).identity());
+
+        let messages = block_on(connect(3).into_stream().collect::<Vec<_>>());
+        assert_eq!(messages, vec![3, 30]);
+    }
+
     #[test]
     fn map_transforms_messages() {
         let subscription = Subscription::from_stream(futures::stream::iter(vec![1, 2, 3]));
@@ -320,6 +718,92 @@ mod tests {
         assert_eq!(doubled, vec![2, 4, 6]);
     }
 
+    #[test]
+    fn system_theme_listeners_survive_batch_and_map() {
+        let subscription = Subscription::batch(vec![
+            Subscription::interval(Duration::from_secs(60), "tick"),
+            Subscription::system_theme(|theme| match theme {
+                egui::Theme::Dark => "dark",
+                egui::Theme::Light => "light",
+            }),
+        ])
+        .map(str::to_uppercase);
+
+        let (_, mut listeners) = subscription.into_parts();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(
+            (listeners[0].callback)(&FrameEvent::SystemThemeChanged(egui::Theme::Dark)),
+            Some("DARK".to_owned())
+        );
+    }
+
+    #[test]
+    fn map_survives_a_panicking_stream() {
+        let subscription = Subscription::batch(vec![
+            Subscription::from_stream(futures::stream::iter(vec![0])),
+            Subscription::on_event(|_| Some(1)),
+        ])
+        .map(|value: u32| 10 / value);
+        let (mut children, mut listeners) = subscription.into_parts();
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            block_on(children[0].stream.next())
+        }));
+        assert!(panicked.is_err());
+
+        let event = egui::Event::PointerGone;
+        assert_eq!(
+            (listeners[0].callback)(&FrameEvent::Input(&event)),
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn key_pressed_reports_shortcuts() {
+        let save = egui::KeyboardShortcut::new(egui::Modifiers::COMMAND, egui::Key::S);
+        let subscription =
+            Subscription::key_pressed(move |shortcut| (shortcut == save).then_some("save"));
+        let (_, mut listeners) = subscription.into_parts();
+        let key = |key, pressed| egui::Event::Key {
+            key,
+            physical_key: None,
+            pressed,
+            repeat: false,
+            modifiers: egui::Modifiers::COMMAND,
+        };
+
+        assert_eq!(
+            (listeners[0].callback)(&FrameEvent::Input(&key(egui::Key::S, true))),
+            Some("save")
+        );
+        assert_eq!(
+            (listeners[0].callback)(&FrameEvent::Input(&key(egui::Key::S, false))),
+            None
+        );
+        assert_eq!(
+            (listeners[0].callback)(&FrameEvent::Input(&key(egui::Key::A, true))),
+            None
+        );
+    }
+
+    #[test]
+    fn on_frame_keeps_animating_through_batch_and_map() {
+        let subscription = Subscription::batch(vec![
+            Subscription::none(),
+            Subscription::on_frame(|dt, _| dt),
+        ])
+        .map(|dt| dt.as_millis());
+        assert!(subscription.animates());
+        assert!(!Subscription::<()>::none().animates());
+
+        let (_, mut listeners) = subscription.into_parts();
+        let frame = FrameEvent::Frame {
+            dt: Duration::from_millis(16),
+            now: Instant::now(),
+        };
+        assert_eq!((listeners[0].callback)(&frame), Some(16));
+    }
+
     #[test]
     fn interval_emits_multiple_messages() {
         let subscription = Subscription::interval(Duration::from_millis(5), 42);
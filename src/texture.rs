@@ -0,0 +1,194 @@
+use std::{collections::HashMap, fmt};
+
+#[cfg(feature = "image")]
+use std::{io, path::PathBuf, sync::Arc};
+
+/// Image data accepted by [`Command::load_texture`](crate::command::Command::load_texture).
+///
+/// Encoded bytes and files require the `image` feature.
+#[derive(Clone, Debug)]
+pub enum TextureSource {
+    /// Already decoded pixels, uploaded as is.
+    Image(egui::ColorImage),
+    /// Encoded image bytes, e.g. the contents of a PNG or JPEG file.
+    #[cfg(feature = "image")]
+    Bytes(Arc<[u8]>),
+    /// Path of an image file that is read and decoded.
+    #[cfg(feature = "image")]
+    Path(PathBuf),
+}
+
+impl TextureSource {
+    /// Decodes the source into pixels. Blocks on file access and decoding.
+    pub(crate) fn decode(self) -> Result<egui::ColorImage, TextureError> {
+        match self {
+            Self::Image(image) => Ok(image),
+            #[cfg(feature = "image")]
+            Self::Bytes(bytes) => decode_bytes(&bytes),
+            #[cfg(feature = "image")]
+            Self::Path(path) => decode_bytes(&std::fs::read(path)?),
+        }
+    }
+}
+
+#[cfg(feature = "image")]
+fn decode_bytes(bytes: &[u8]) -> Result<egui::ColorImage, TextureError> {
+    let image = image::load_from_memory(bytes)
+        .map_err(|error| TextureError::Decode(error.to_string()))?
+        .to_rgba8();
+    let size = [image.width() as usize, image.height() as usize];
+    Ok(egui::ColorImage::from_rgba_unmultiplied(
+        size,
+        image.as_raw(),
+    ))
+}
+
+impl From<egui::ColorImage> for TextureSource {
+    fn from(image: egui::ColorImage) -> Self {
+        Self::Image(image)
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<Vec<u8>> for TextureSource {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Bytes(bytes.into())
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<&'static [u8]> for TextureSource {
+    fn from(bytes: &'static [u8]) -> Self {
+        Self::Bytes(bytes.into())
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<Arc<[u8]>> for TextureSource {
+    fn from(bytes: Arc<[u8]>) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<PathBuf> for TextureSource {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<&std::path::Path> for TextureSource {
+    fn from(path: &std::path::Path) -> Self {
+        Self::Path(path.to_path_buf())
+    }
+}
+
+/// Reason a texture could not be loaded.
+#[derive(Debug)]
+pub enum TextureError {
+    /// The image file could not be read.
+    #[cfg(feature = "image")]
+    Io(io::Error),
+    /// The bytes are not an image in a supported format.
+    Decode(String),
+}
+
+impl fmt::Display for TextureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "image")]
+            Self::Io(error) => write!(f, "failed to read image: {error}"),
+            Self::Decode(error) => write!(f, "failed to decode image: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for TextureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "image")]
+            Self::Io(error) => Some(error),
+            Self::Decode(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<io::Error> for TextureError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// Handles loaded through commands, stored in the context's temporary data by name.
+#[derive(Clone, Default)]
+struct TextureCache(HashMap<String, egui::TextureHandle>);
+
+impl TextureCache {
+    fn id() -> egui::Id {
+        egui::Id::new("egui_elm::textures")
+    }
+}
+
+/// Returns the handle previously loaded under `name`.
+pub(crate) fn cached(ctx: &egui::Context, name: &str) -> Option<egui::TextureHandle> {
+    ctx.data_mut(|data| {
+        data.get_temp_mut_or_default::<TextureCache>(TextureCache::id())
+            .0
+            .get(name)
+            .cloned()
+    })
+}
+
+/// Uploads the image and remembers the handle under `name`.
+pub(crate) fn upload(
+    ctx: &egui::Context,
+    name: String,
+    image: egui::ColorImage,
+    options: egui::TextureOptions,
+) -> egui::TextureHandle {
+    let handle = ctx.load_texture(name.clone(), image, options);
+    ctx.data_mut(|data| {
+        data.get_temp_mut_or_default::<TextureCache>(TextureCache::id())
+            .0
+            .insert(name, handle.clone());
+    });
+    handle
+}
+
+/// Drops the cached handle, freeing the texture once no other handle refers to it.
+pub(crate) fn forget(ctx: &egui::Context, name: &str) {
+    ctx.data_mut(|data| {
+        data.get_temp_mut_or_default::<TextureCache>(TextureCache::id())
+            .0
+            .remove(name);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_keeps_uploaded_handles_until_forgotten() {
+        let ctx = egui::Context::default();
+        let image = egui::ColorImage::filled([2, 2], egui::Color32::RED);
+
+        let handle = upload(&ctx, "red".into(), image, Default::default());
+        assert_eq!(
+            cached(&ctx, "red").map(|cached| cached.id()),
+            Some(handle.id())
+        );
+
+        forget(&ctx, "red");
+        assert!(cached(&ctx, "red").is_none());
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn invalid_bytes_fail_to_decode() {
+        let source = TextureSource::from(vec![0_u8, 1, 2, 3]);
+        assert!(matches!(source.decode(), Err(TextureError::Decode(_))));
+    }
+}
@@ -1,7 +1,7 @@
 use egui::Context;
 
 #[cfg(feature = "runtime")]
-type ViewSender<Message> = tokio::sync::mpsc::Sender<Message>;
+type ViewSender<Message> = tokio::sync::mpsc::Sender<crate::app::Envelope<Message>>;
 
 #[cfg(not(feature = "runtime"))]
 type ViewSender<Message> = std::sync::mpsc::Sender<Message>;
@@ -13,6 +13,11 @@ where
     Message: Send + 'static,
 {
     sender: ViewSender<Message>,
+    #[cfg(feature = "runtime")]
+    pending: std::sync::Arc<[crate::app::PendingTask]>,
+    /// Woken after a send, so messages sent from outside the view are handled promptly.
+    #[cfg(feature = "runtime")]
+    ctx: Option<Context>,
 }
 
 impl<Message> ViewContext<Message>
@@ -21,7 +26,13 @@ where
 {
     #[cfg_attr(not(feature = "runtime"), allow(dead_code))]
     pub(crate) fn new(sender: ViewSender<Message>) -> Self {
-        Self { sender }
+        Self {
+            sender,
+            #[cfg(feature = "runtime")]
+            pending: std::sync::Arc::new([]),
+            #[cfg(feature = "runtime")]
+            ctx: None,
+        }
     }
 }
 
@@ -32,7 +43,41 @@ where
 {
     /// Sends a message back to the Elm program without blocking the UI thread.
     pub fn send(&self, message: Message) {
-        let _ = self.sender.try_send(message);
+        let _ = self
+            .sender
+            .try_send(crate::app::Envelope::untracked(message));
+        if let Some(ctx) = &self.ctx {
+            ctx.request_repaint();
+        }
+    }
+
+    /// Returns the labeled command tasks that are still running, oldest first.
+    ///
+    /// Only tasks named with [`Command::with_label`](crate::command::Command::with_label) or
+    /// [`Command::labeled`](crate::command::Command::labeled) are listed.
+    pub fn pending_tasks(&self) -> &[crate::app::PendingTask] {
+        &self.pending
+    }
+
+    /// Returns how many running tasks carry `label`.
+    pub fn pending(&self, label: &str) -> usize {
+        self.pending
+            .iter()
+            .filter(|task| task.label() == label)
+            .count()
+    }
+
+    pub(crate) fn with_pending_tasks(
+        mut self,
+        pending: std::sync::Arc<[crate::app::PendingTask]>,
+    ) -> Self {
+        self.pending = pending;
+        self
+    }
+
+    pub(crate) fn with_repaint(mut self, ctx: Context) -> Self {
+        self.ctx = Some(ctx);
+        self
     }
 }
 